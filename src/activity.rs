@@ -1,7 +1,7 @@
 use crate::currency::{Builder, Currency, Eur, Gbp, Pln, Rates, Usd};
 use crate::nbp::Rate;
 use chrono::naive::serde::ts_seconds;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
 use rust_decimal::Decimal;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
@@ -19,6 +19,61 @@ pub struct Money {
     pub rate: Option<Rate>,
 }
 
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Instrument {
+    pub symbol: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exchange: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contract_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derivative: Option<Derivative>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum DerivativeKind {
+    Option,
+    Future,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum Right {
+    Call,
+    Put,
+}
+
+/// Contract terms of an option or future, prices are quoted per unit of the underlying.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Derivative {
+    pub kind: DerivativeKind,
+    pub underlying: String,
+    pub multiplier: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strike: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub right: Option<Right>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum FeeKind {
+    /// Account maintenance, custody and ADR fees.
+    Custody,
+    MarketData,
+    Other,
+}
+
+/// Lot carried over from another broker, cost includes buy commission.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransferLot {
+    pub date: NaiveDate,
+    pub quantity: Decimal,
+    pub cost: Pln,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Operation {
     Buy {
@@ -34,12 +89,140 @@ pub enum Operation {
     Dividend {
         value: Money,
         withholding_tax: Money,
+        #[serde(default)]
+        country: Option<String>,
+    },
+    /// Payment in lieu of dividend on lent shares, tax withheld is not creditable.
+    PaymentInLieu {
+        value: Money,
+        withholding_tax: Money,
+    },
+    Split {
+        numerator: Decimal,
+        denominator: Decimal,
+    },
+    Rename {
+        symbol: String,
+        #[serde(default)]
+        isin: Option<String>,
+    },
+    Merger {
+        symbol: String,
+        #[serde(default)]
+        isin: Option<String>,
+        numerator: Decimal,
+        denominator: Decimal,
+    },
+    SpinOff {
+        symbol: String,
+        #[serde(default)]
+        isin: Option<String>,
+        numerator: Decimal,
+        denominator: Decimal,
+        cost_fraction: Decimal,
+    },
+    TransferOut {
+        quantity: Decimal,
+    },
+    TransferIn {
+        quantity: Decimal,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        lots: Vec<TransferLot>,
+    },
+    Expiry {
+        quantity: Decimal,
+    },
+    Forex {
+        sold: Money,
+        bought: Money,
+        commission: Money,
+    },
+    Interest {
+        value: Money,
+        withholding_tax: Money,
+    },
+    InterestPaid {
+        value: Money,
+    },
+    Fee {
+        value: Money,
+        kind: FeeKind,
+        description: String,
+    },
+    Assignment {
+        quantity: Decimal,
+    },
+    /// Change of tax withheld on a dividend paid on `dividend_date`, refunds are negative.
+    /// Adjustments without a date are linked to the latest earlier dividend of the symbol.
+    WithholdingAdjustment {
+        value: Money,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dividend_date: Option<NaiveDate>,
     },
 }
 
+impl Instrument {
+    pub fn new(symbol: &str) -> Instrument {
+        Instrument {
+            symbol: symbol.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns number of underlying units per contract, one for stocks.
+    pub fn multiplier(&self) -> Decimal {
+        self.derivative
+            .as_ref()
+            .map_or(Decimal::ONE, |derivative| derivative.multiplier)
+    }
+
+    /// Parses ISIN enclosed in parentheses, e.g. "AAPL(US0378331005) Cash Dividend".
+    pub fn parse_isin(text: &str) -> Option<String> {
+        text.split(['(', ')', ' '])
+            .find(|word| is_isin(word))
+            .map(|isin| isin.to_string())
+    }
+}
+
+fn is_isin(value: &str) -> bool {
+    value.len() == 12
+        && value[..2].chars().all(|c| c.is_ascii_uppercase())
+        && value[2..].chars().all(|c| c.is_ascii_alphanumeric())
+        && value.chars().last().is_some_and(|c| c.is_ascii_digit())
+}
+
+impl FeeKind {
+    /// Returns whether fee is a cost of holding securities, as opposed to optional services.
+    pub fn is_deductible(&self) -> bool {
+        matches!(self, FeeKind::Custody)
+    }
+}
+
+impl Operation {
+    pub fn is_corporate_action(&self) -> bool {
+        matches!(
+            self,
+            Operation::Split { .. }
+                | Operation::Rename { .. }
+                | Operation::Merger { .. }
+                | Operation::SpinOff { .. }
+        )
+    }
+
+    pub fn is_transfer(&self) -> bool {
+        matches!(
+            self,
+            Operation::TransferOut { .. } | Operation::TransferIn { .. }
+        )
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Activity {
-    pub symbol: String,
+    #[serde(flatten)]
+    pub instrument: Instrument,
+    #[serde(default)]
+    pub account: String,
     #[serde(with = "ts_seconds")]
     pub timestamp: NaiveDateTime,
     pub operation: Operation,
@@ -51,7 +234,7 @@ pub struct Document {
     pub rates: Rates,
 }
 
-fn from_currency<'de, D>(deserializer: D) -> Result<Box<dyn Currency>, D::Error>
+pub fn from_currency<'de, D>(deserializer: D) -> Result<Box<dyn Currency>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -74,6 +257,15 @@ where
     }
 }
 
+pub fn from_optional_currency<'de, D>(
+    deserializer: D,
+) -> Result<Option<Box<dyn Currency>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    from_currency(deserializer).map(Some)
+}
+
 fn to_currency<S>(currency: &Box<dyn Currency>, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
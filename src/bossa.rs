@@ -0,0 +1,115 @@
+use crate::activity::{Activity, Instrument};
+use crate::currency;
+use crate::mbank::{from_float, read_transactions, Trade};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error::Error;
+use std::path::Path;
+
+/// Row of DM BOŚ transaction history, amounts are in PLN unless currency is reported.
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(rename(deserialize = "Data"), deserialize_with = "from_timestamp")]
+    timestamp: NaiveDateTime,
+    #[serde(rename(deserialize = "Walor"))]
+    symbol: String,
+    #[serde(rename(deserialize = "K/S"))]
+    operation: Operation,
+    #[serde(rename(deserialize = "Liczba"), deserialize_with = "from_float")]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Kurs"), deserialize_with = "from_float")]
+    price: Decimal,
+    #[serde(rename(deserialize = "Prowizja"), deserialize_with = "from_float")]
+    commission: Decimal,
+    #[serde(rename(deserialize = "Waluta"), default)]
+    currency: Option<currency::Code>,
+}
+
+#[derive(Debug, Deserialize)]
+enum Operation {
+    #[serde(rename(deserialize = "K"))]
+    Buy,
+    #[serde(rename(deserialize = "S"))]
+    Sell,
+}
+
+fn from_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp: &str = Deserialize::deserialize(deserializer)?;
+    let formats = ["%Y-%m-%d %H:%M:%S", "%d.%m.%Y %H:%M:%S"];
+    formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(timestamp, "%Y-%m-%d")
+                .ok()
+                .map(|date| NaiveDateTime::new(date, NaiveTime::MIN))
+        })
+        .ok_or(de::Error::custom(format!(
+            "Failed to parse \"{}\"",
+            timestamp
+        )))
+}
+
+impl From<Transaction> for Activity {
+    fn from(transaction: Transaction) -> Activity {
+        let currency = transaction.currency.unwrap_or(currency::Code::PLN);
+        Trade {
+            instrument: Instrument::new(&transaction.symbol),
+            timestamp: transaction.timestamp,
+            buy: matches!(transaction.operation, Operation::Buy),
+            quantity: transaction.quantity,
+            price: transaction.price,
+            currency,
+            commission: transaction.commission,
+            commission_currency: currency,
+        }
+        .into()
+    }
+}
+
+pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let transactions = read_transactions::<Transaction>(path)?;
+    Ok(transactions.into_iter().map(|entry| entry.into()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity;
+    use encoding_rs::WINDOWS_1250;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_convert() {
+        let statement = "\
+Data;Walor;Giełda;K/S;Liczba;Kurs;Wartość;Prowizja;Waluta
+2023-01-05 10:15:22;PKNORLEN;GPW;K;20;62,50;1 250,00;4,88;PLN
+2023-03-10 15:02:01;PKNORLEN;GPW;S;20;70,10;1 402,00;5,47;
+";
+        let path = env::temp_dir().join("stock_tax_test_bossa.csv");
+        fs::write(&path, WINDOWS_1250.encode(statement).0).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 2);
+        assert_eq!(activities[0].instrument.symbol, "PKNORLEN");
+        assert!(matches!(
+            &activities[0].operation,
+            activity::Operation::Buy { quantity, price, commission }
+                if *quantity == dec!(20)
+                    && *price.original.get_value() == dec!(62.50)
+                    && *commission.original.get_value() == dec!(4.88)
+        ));
+        assert!(matches!(
+            &activities[1].operation,
+            activity::Operation::Sell { commission, .. }
+                if commission.original.get_code() == currency::Code::PLN
+        ));
+    }
+}
@@ -1,14 +1,19 @@
-use crate::activity::{Activity, Money, Operation};
-use crate::currency::{Builder, Pln};
+use crate::activity::{Activity, FeeKind, Instrument, Money, Operation, Right, TransferLot};
+use crate::currency::{Builder, Currency, Pln};
+use crate::identity::Identities;
+use crate::loss::{self, Ledger, Loss};
+use crate::reconcile;
 use crate::tax::Tax;
+use crate::treaty::Treaties;
 use chrono::Datelike;
-use chrono::NaiveDateTime;
-use clap::Args;
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, ValueEnum};
 use colored::Colorize;
 use derive_more::{AddAssign, Display, Error};
 use glob::glob;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::Deserialize;
 use serde_json;
 use std::cmp::min;
 use std::collections::{HashMap, HashSet, VecDeque};
@@ -24,74 +29,1027 @@ pub struct Error {
 #[derive(Args)]
 pub struct CommandArgs {
     path: String,
+    #[command(flatten)]
+    matching: MatchingArgs,
+    #[arg(long, value_enum, default_value_t = loss::Strategy::Half)]
+    loss_strategy: loss::Strategy,
+    #[arg(long)]
+    losses: Option<String>,
+    #[arg(long)]
+    treaty: Option<String>,
 }
 
+#[derive(Args, Default)]
+pub struct MatchingArgs {
+    #[arg(long, value_enum, default_value_t = MatchingMethod::Fifo)]
+    matching: MatchingMethod,
+    #[arg(long)]
+    lots: Option<String>,
+    #[arg(long)]
+    allow_short: bool,
+}
+
+#[derive(Display, Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum MatchingMethod {
+    #[default]
+    #[display(fmt = "FIFO")]
+    Fifo,
+    #[display(fmt = "LIFO")]
+    Lifo,
+    #[display(fmt = "highest cost")]
+    HighestCost,
+    #[display(fmt = "specific lot")]
+    SpecificLot,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lot {
+    date: NaiveDate,
+    quantity: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct LotSelection {
+    symbol: String,
+    date: NaiveDate,
+    lots: Vec<Lot>,
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub symbol: String,
+    pub account: String,
+    pub timestamp: NaiveDateTime,
+    pub quantity: Decimal,
+    pub cost: Pln,
+    pub commission: Pln,
+}
+
+/// Option premium awaiting the underlying trade resulting from exercise or assignment.
 #[derive(Debug)]
-struct Block {
+struct Premium {
+    symbol: String,
     timestamp: NaiveDateTime,
-    quantity: Decimal,
-    price: Pln,
-    commission: Pln,
+    right: Right,
+    written: bool,
+    amount: Pln,
+}
+
+/// Transfer between accounts awaiting its counterpart.
+#[derive(Debug)]
+enum Transfer {
+    Out {
+        account: String,
+        timestamp: NaiveDateTime,
+        quantity: Decimal,
+        blocks: Vec<Block>,
+    },
+    In {
+        symbol: String,
+        account: String,
+        timestamp: NaiveDateTime,
+        quantity: Decimal,
+    },
 }
 
-#[derive(Debug, Default)]
-struct Stock {
-    blocks: HashMap<String, VecDeque<Block>>,
+#[derive(Debug)]
+pub struct Stock {
+    blocks: HashMap<(String, String), VecDeque<Block>>,
+    transfers: HashMap<String, Vec<Transfer>>,
+    shorts: HashMap<(String, String), VecDeque<Block>>,
+    deferred: HashMap<i32, Pln>,
+    premiums: HashMap<(String, String), Vec<Premium>>,
+    allow_short: bool,
+    method: MatchingMethod,
+    selections: Vec<LotSelection>,
+    identities: Identities,
+    log: bool,
+}
+
+#[derive(Debug)]
+pub struct LotMatch {
+    pub timestamp: NaiveDateTime,
+    pub quantity: Decimal,
+    pub price: Pln,
+    pub cost: Pln,
+    pub commission: Pln,
+}
+
+#[derive(Debug)]
+pub struct Sale {
+    pub symbol: String,
+    pub timestamp: NaiveDateTime,
+    pub quantity: Decimal,
+    pub revenue: Pln,
+    pub cost: Pln,
+    pub commission: Pln,
+    pub lots: Vec<LotMatch>,
+    pub short: bool,
+    pub derivative: bool,
+    /// Option premium moved into the cost on exercise or assignment.
+    pub adjustment: Pln,
 }
 
 #[derive(Default)]
-struct TaxPosition {
-    dividend: Pln,
-    dividend_withholding_tax: Pln,
-    stock_revenue: Pln,
-    stock_cost: Pln,
+pub struct TaxPosition {
+    pub dividend: Pln,
+    pub dividend_withholding_tax: Pln,
+    pub dividend_creditable_tax: Pln,
+    pub stock_revenue: Pln,
+    pub stock_cost: Pln,
+    pub derivative: bool,
 }
 
 #[derive(Default, AddAssign)]
-struct TaxReturn {
-    dividend_tax: Pln,
-    stock_revenue: Pln,
-    stock_cost: Pln,
-    stock_income: Pln,
-    stock_loss: Pln,
-    stock_tax: Pln,
+pub struct TaxReturn {
+    pub dividend_tax: Pln,
+    pub dividend_excess_tax: Pln,
+    pub stock_revenue: Pln,
+    pub stock_cost: Pln,
+    pub stock_income: Pln,
+    pub stock_loss: Pln,
+    pub derivative_revenue: Pln,
+    pub derivative_cost: Pln,
+}
+
+/// PIT-38 section C, stock revenue and cost are totals including derivatives reported as other revenue.
+#[derive(Default)]
+pub struct Pit38 {
+    pub stock_revenue: Pln,
+    pub stock_cost: Pln,
+    pub derivative_revenue: Pln,
+    pub derivative_cost: Pln,
+    pub stock_income: Pln,
+    pub stock_loss: Pln,
+    pub loss_deduction: Pln,
+    pub tax_base: Pln,
+    pub stock_tax: Pln,
+}
+
+impl MatchingArgs {
+    pub fn method(&self) -> MatchingMethod {
+        self.matching
+    }
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
 }
 
 impl Block {
-    fn new(
-        timestamp: &NaiveDateTime,
-        quantity: &Decimal,
-        price: &Money,
-        commission: &Money,
-    ) -> Block {
+    fn new(activity: &Activity, quantity: &Decimal, price: &Money, commission: Pln) -> Block {
         Block {
-            timestamp: *timestamp,
+            symbol: activity.instrument.symbol.to_string(),
+            account: activity.account.to_string(),
+            timestamp: activity.timestamp,
             quantity: *quantity,
-            price: price.pln,
-            commission: commission.pln,
+            cost: price.pln * (*quantity * activity.instrument.multiplier()),
+            commission,
+        }
+    }
+
+    pub fn price(&self) -> Pln {
+        match self.quantity.is_zero() {
+            true => Pln::default(),
+            false => self.cost / self.quantity,
         }
     }
+
+    /// Returns unrounded cost per unit used to order lots, zero for an empty block.
+    fn unit_cost(&self) -> Decimal {
+        match self.quantity.is_zero() {
+            true => Decimal::ZERO,
+            false => self.cost.get_value() / self.quantity,
+        }
+    }
+
+    /// Removes given quantity from the block, returns its cost and buy commission
+    /// once the block is fully consumed.
+    fn take(&mut self, quantity: Decimal) -> (Pln, Pln) {
+        if quantity == self.quantity {
+            let taken = (self.cost, self.commission);
+            self.quantity = Decimal::ZERO;
+            self.cost = Pln::default();
+            return taken;
+        }
+
+        let cost = self.cost * (quantity / self.quantity);
+        self.quantity -= quantity;
+        self.cost = self.cost - cost;
+        (cost, Pln::default())
+    }
 }
 
 impl Stock {
-    fn get_blocks(&mut self, symbol: String) -> &mut VecDeque<Block> {
-        self.blocks.entry(symbol).or_default()
+    fn new(
+        method: MatchingMethod,
+        selections: Vec<LotSelection>,
+        identities: Identities,
+        allow_short: bool,
+        log: bool,
+    ) -> Stock {
+        Stock {
+            blocks: HashMap::new(),
+            transfers: HashMap::new(),
+            shorts: HashMap::new(),
+            deferred: HashMap::new(),
+            premiums: HashMap::new(),
+            allow_short,
+            method,
+            selections,
+            identities,
+            log,
+        }
+    }
+
+    pub fn method(&self) -> MatchingMethod {
+        self.method
+    }
+
+    /// Returns open lots grouped by account and instrument key.
+    pub fn positions(&self) -> impl Iterator<Item = (&(String, String), &VecDeque<Block>)> {
+        self.blocks.iter().filter(|(_, blocks)| !blocks.is_empty())
+    }
+
+    /// Returns accounts holding open lots of the instrument.
+    pub fn accounts(&self, instrument: &Instrument) -> Vec<String> {
+        let identity = self.identities.key(instrument);
+        let mut accounts: Vec<_> = self
+            .positions()
+            .filter(|((_, key), _)| *key == identity)
+            .map(|((account, _), _)| account.to_string())
+            .collect();
+        accounts.sort();
+        accounts
+    }
+
+    /// Returns open short lots grouped by account and instrument key, cost holds sale proceeds.
+    pub fn short_positions(&self) -> impl Iterator<Item = (&(String, String), &VecDeque<Block>)> {
+        self.shorts.iter().filter(|(_, blocks)| !blocks.is_empty())
+    }
+
+    /// Returns proceeds of short sales opened in the year, realized only once closed.
+    pub fn deferred(&self, year: i32) -> Pln {
+        self.deferred.get(&year).copied().unwrap_or_default()
     }
 
-    fn buy(&mut self, activity: &Activity, quantity: &Decimal, price: &Money, commission: &Money) {
-        println!("{date}: {symbol}: {prefix} quantity: {quantity} price: {price_pln} ({price_org}) commission: {commission_pln} ({commission_org})",
+    /// Applies activity affecting open lots, returns sale details for sell operations.
+    pub fn apply(&mut self, activity: &Activity) -> Result<Option<Sale>, Error> {
+        match &activity.operation {
+            Operation::Buy {
+                quantity,
+                price,
+                commission,
+            } => Ok(self.buy(activity, quantity, price, commission)),
+            Operation::Sell {
+                quantity,
+                price,
+                commission,
+            } => self.sell(activity, quantity, price, commission),
+            Operation::Split {
+                numerator,
+                denominator,
+            } => {
+                self.split(activity, numerator, denominator);
+                Ok(None)
+            }
+            Operation::Rename { symbol, isin } => {
+                self.exchange(activity, symbol, isin, &dec!(1), &dec!(1));
+                Ok(None)
+            }
+            Operation::Merger {
+                symbol,
+                isin,
+                numerator,
+                denominator,
+            } => {
+                self.exchange(activity, symbol, isin, numerator, denominator);
+                Ok(None)
+            }
+            Operation::SpinOff {
+                symbol,
+                isin,
+                numerator,
+                denominator,
+                cost_fraction,
+            } => {
+                self.spin_off(
+                    activity,
+                    symbol,
+                    isin,
+                    numerator,
+                    denominator,
+                    cost_fraction,
+                )?;
+                Ok(None)
+            }
+            Operation::TransferOut { quantity } => {
+                self.transfer_out(activity, quantity)?;
+                Ok(None)
+            }
+            Operation::TransferIn { quantity, lots } => {
+                self.transfer_in(activity, quantity, lots)?;
+                Ok(None)
+            }
+            Operation::Expiry { quantity } => self.expire(activity, quantity).map(Some),
+            Operation::Assignment { quantity } => {
+                self.assign(activity, quantity)?;
+                Ok(None)
+            }
+            Operation::Dividend { .. }
+            | Operation::PaymentInLieu { .. }
+            | Operation::Forex { .. }
+            | Operation::Interest { .. }
+            | Operation::InterestPaid { .. }
+            | Operation::Fee { .. }
+            | Operation::WithholdingAdjustment { .. } => Ok(None),
+        }
+    }
+
+    /// Fails if any exercised or assigned option has no underlying trade to carry its premium.
+    pub fn verify_assignments(&self) -> Result<(), Error> {
+        let unmatched: Vec<_> = self
+            .premiums
+            .values()
+            .flatten()
+            .map(|premium| {
+                format!(
+                    "{date}: {symbol}: premium {amount} has no matching underlying trade",
+                    date = premium.timestamp.date(),
+                    symbol = premium.symbol,
+                    amount = premium.amount,
+                )
+            })
+            .collect();
+
+        match unmatched.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(&format!(
+                "Unmatched option assignments: {}",
+                unmatched.join("; ")
+            ))),
+        }
+    }
+
+    /// Fails if any lot selection was not used by a sale.
+    pub fn verify_selections(&self) -> Result<(), Error> {
+        let unused: Vec<_> = self
+            .selections
+            .iter()
+            .map(|selection| format!("{} on {}", selection.symbol, selection.date))
+            .collect();
+
+        match unused.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(&format!(
+                "Lot selections without matching sale: {}",
+                unused.join("; ")
+            ))),
+        }
+    }
+
+    /// Fails if any transfer out has no matching transfer in or vice versa.
+    pub fn verify_transfers(&self) -> Result<(), Error> {
+        let unmatched: Vec<_> = self
+            .transfers
+            .values()
+            .flatten()
+            .map(|transfer| match transfer {
+                Transfer::Out {
+                    account,
+                    timestamp,
+                    quantity,
+                    blocks,
+                } => format!(
+                    "{date}: {symbol}: transfer out of {quantity} shares from {account} has no matching transfer in",
+                    date = timestamp.date(),
+                    symbol = blocks.first().map_or("", |block| &block.symbol),
+                ),
+                Transfer::In {
+                    symbol,
+                    account,
+                    timestamp,
+                    quantity,
+                } => format!(
+                    "{date}: {symbol}: transfer in of {quantity} shares to {account} has no matching transfer out nor lots",
+                    date = timestamp.date(),
+                ),
+            })
+            .collect();
+
+        match unmatched.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(&format!(
+                "Unbalanced transfers: {}",
+                unmatched.join("; ")
+            ))),
+        }
+    }
+
+    fn get_blocks(&mut self, key: (String, String)) -> &mut VecDeque<Block> {
+        self.blocks.entry(key).or_default()
+    }
+
+    fn key(&self, activity: &Activity) -> (String, String) {
+        (
+            activity.account.to_string(),
+            self.identities.key(&activity.instrument),
+        )
+    }
+
+    fn buy(
+        &mut self,
+        activity: &Activity,
+        quantity: &Decimal,
+        price: &Money,
+        commission: &Money,
+    ) -> Option<Sale> {
+        if self.log {
+            println!("{date}: {symbol}: {prefix} quantity: {quantity} price: {price_pln} ({price_org}) commission: {commission_pln} ({commission_org})",
             date=activity.timestamp.date(),
-            symbol=activity.symbol,
+            symbol=activity.instrument.symbol,
             prefix="Buy".green(),
             quantity=quantity,
             price_pln=price.pln,
             price_org=price.original,
             commission_pln=commission.pln,
             commission_org=commission.original);
+        }
+
+        let (increase, decrease) =
+            self.take_premiums(activity, &[(Right::Call, false)], &[(Right::Put, true)]);
+        let mut sale = self.close_short(activity, quantity, price, commission);
+        let mut block = match &sale {
+            None => Block::new(activity, quantity, price, commission.pln),
+            Some(sale) if sale.quantity < *quantity => Block::new(
+                activity,
+                &(*quantity - sale.quantity),
+                price,
+                commission.pln - sale.commission,
+            ),
+            Some(_) => {
+                if let Some(sale) = &mut sale {
+                    sale.cost = sale.cost + increase - decrease;
+                    sale.adjustment = sale.adjustment + increase - decrease;
+                }
+                return sale;
+            }
+        };
+        block.cost = block.cost + increase - decrease;
+        self.get_blocks(self.key(activity)).push_back(block);
+        sale
+    }
+
+    /// Removes premiums pending for the underlying of the activity, returns total of premiums
+    /// increasing and decreasing the cost.
+    fn take_premiums(
+        &mut self,
+        activity: &Activity,
+        increasing: &[(Right, bool)],
+        decreasing: &[(Right, bool)],
+    ) -> (Pln, Pln) {
+        let mut increase = Pln::default();
+        let mut decrease = Pln::default();
+        let Some(premiums) = self.premiums.get_mut(&self.key(activity)) else {
+            return (increase, decrease);
+        };
+
+        premiums.retain(|premium| {
+            let kind = (premium.right, premium.written);
+            if increasing.contains(&kind) {
+                increase += premium.amount;
+            } else if decreasing.contains(&kind) {
+                decrease += premium.amount;
+            } else {
+                return true;
+            }
+
+            if self.log {
+                println!(
+                    "  {date}: {symbol}: {prefix} {amount}",
+                    date = premium.timestamp.date(),
+                    symbol = premium.symbol,
+                    prefix = "Option premium".magenta(),
+                    amount = premium.amount,
+                );
+            }
+            false
+        });
+        (increase, decrease)
+    }
+
+    /// Closes option lots at zero value.
+    fn expire(&mut self, activity: &Activity, quantity: &Decimal) -> Result<Sale, Error> {
+        let zero = Money {
+            original: Pln::new_box(0),
+            pln: Pln::default(),
+            rate: None,
+        };
+
+        if self.log {
+            println!(
+                "{date}: {symbol}: {prefix} quantity: {quantity}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                prefix = "Expiry".cyan(),
+            );
+        }
+
+        if let Some(sale) = self.close_short(activity, quantity, &zero, &zero) {
+            return Ok(sale);
+        }
 
-        let blocks = self.get_blocks(activity.symbol.to_string());
-        let block = Block::new(&activity.timestamp, quantity, price, commission);
-        blocks.push_back(block);
+        let lots = self.take_lots(activity, quantity)?;
+        let cost = lots
+            .iter()
+            .fold(Pln::default(), |acc, lot| acc + lot.cost + lot.commission);
+        Ok(Sale {
+            symbol: activity.instrument.symbol.to_string(),
+            timestamp: activity.timestamp,
+            quantity: *quantity,
+            revenue: Pln::default(),
+            cost,
+            commission: Pln::default(),
+            lots,
+            short: false,
+            derivative: activity.instrument.derivative.is_some(),
+            adjustment: Pln::default(),
+        })
+    }
+
+    /// Removes exercised or assigned option lots keeping their premium for the underlying trade.
+    fn assign(&mut self, activity: &Activity, quantity: &Decimal) -> Result<(), Error> {
+        let derivative = activity.instrument.derivative.as_ref();
+        let (Some(right), Some(underlying)) = (
+            derivative.and_then(|derivative| derivative.right),
+            derivative.map(|derivative| &derivative.underlying),
+        ) else {
+            return Err(Error::new(&format!(
+                "{date}: {symbol}: Assignment of an instrument which is not an option",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+            )));
+        };
+
+        let key = self.key(activity);
+        let written = self
+            .shorts
+            .get(&key)
+            .is_some_and(|shorts| !shorts.is_empty());
+        let amount = match written {
+            true => {
+                let method = self.automatic_method();
+                let shorts = self.shorts.get_mut(&key).unwrap();
+                let allocations = Self::allocate(method, shorts, quantity, &[]);
+                let mut amount = Pln::default();
+                for (index, quantity) in allocations {
+                    let (proceeds, commission) = shorts[index].take(quantity);
+                    amount = amount + proceeds - commission;
+                }
+                shorts.retain(|block| block.quantity > dec!(0));
+                amount
+            }
+            false => self
+                .take_lots(activity, quantity)?
+                .iter()
+                .fold(Pln::default(), |acc, lot| acc + lot.cost + lot.commission),
+        };
+
+        if self.log {
+            println!(
+                "{date}: {symbol}: {prefix} quantity: {quantity} underlying: {underlying} premium: {amount}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                prefix = "Assignment".cyan(),
+            );
+        }
+
+        let key = (
+            activity.account.to_string(),
+            self.identities.resolve(underlying, &None),
+        );
+        self.premiums.entry(key).or_default().push(Premium {
+            symbol: activity.instrument.symbol.to_string(),
+            timestamp: activity.timestamp,
+            right,
+            written,
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Removes given quantity from long lots without lot selection.
+    fn take_lots(
+        &mut self,
+        activity: &Activity,
+        quantity: &Decimal,
+    ) -> Result<Vec<LotMatch>, Error> {
+        let method = self.automatic_method();
+        let blocks = self.get_blocks(self.key(activity));
+        let allocations = Self::allocate(method, blocks, quantity, &[]);
+        let allocated = allocations
+            .iter()
+            .fold(dec!(0), |acc, (_, quantity)| acc + quantity);
+        if allocated != *quantity {
+            return Err(Error::new(&format!(
+                "{date}: {symbol}: Only {allocated} of {quantity} contracts matched open lots",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+            )));
+        }
+
+        let mut lots = vec![];
+        for (index, quantity) in allocations {
+            let block = &mut blocks[index];
+            let price = block.price();
+            let (cost, commission) = block.take(quantity);
+            lots.push(LotMatch {
+                timestamp: block.timestamp,
+                quantity,
+                price,
+                cost,
+                commission,
+            });
+        }
+        blocks.retain(|block| block.quantity > dec!(0));
+        Ok(lots)
+    }
+
+    fn split(&mut self, activity: &Activity, numerator: &Decimal, denominator: &Decimal) {
+        let log = self.log;
+        if let Some(shorts) = self.shorts.get_mut(&self.key(activity)) {
+            for block in shorts.iter_mut() {
+                block.quantity = block.quantity * numerator / denominator;
+            }
+        }
+
+        let blocks = self.get_blocks(self.key(activity));
+        let before = blocks
+            .iter()
+            .fold(dec!(0), |acc, block| acc + block.quantity);
+
+        for block in blocks.iter_mut() {
+            block.quantity = block.quantity * numerator / denominator;
+        }
+
+        if log {
+            println!("{date}: {symbol}: {prefix} ratio: {numerator}:{denominator} quantity: {before} -> {after}",
+                date=activity.timestamp.date(),
+                symbol=activity.instrument.symbol,
+                prefix="Split".cyan(),
+                after=blocks.iter().fold(dec!(0), |acc, block| acc + block.quantity));
+        }
+    }
+
+    /// Exchanges all long and short lots for lots of another symbol keeping acquisition dates
+    /// and total cost.
+    fn exchange(
+        &mut self,
+        activity: &Activity,
+        symbol: &str,
+        isin: &Option<String>,
+        numerator: &Decimal,
+        denominator: &Decimal,
+    ) {
+        let source = self.key(activity);
+        let target = (
+            activity.account.to_string(),
+            self.identities.resolve(symbol, isin),
+        );
+        let exchange = |lots: &mut HashMap<(String, String), VecDeque<Block>>| {
+            let blocks = lots.remove(&source).unwrap_or_default();
+            let quantity = blocks
+                .iter()
+                .fold(dec!(0), |acc, block| acc + block.quantity);
+            let target = lots.entry(target.clone()).or_default();
+            target.extend(blocks.into_iter().map(|mut block| {
+                block.symbol = symbol.to_string();
+                block.quantity = block.quantity * numerator / denominator;
+                block
+            }));
+            target
+                .make_contiguous()
+                .sort_by_key(|block| block.timestamp);
+            quantity
+        };
+        let before = exchange(&mut self.blocks);
+        exchange(&mut self.shorts);
+
+        if self.log {
+            println!("{date}: {symbol}: {prefix} to: {target} ratio: {numerator}:{denominator} quantity: {before} -> {after}",
+                date=activity.timestamp.date(),
+                symbol=activity.instrument.symbol,
+                prefix="Exchange".cyan(),
+                target=symbol,
+                after=before * numerator / denominator);
+        }
+    }
+
+    /// Creates lots of spun-off company, moving given fraction of the cost from parent lots.
+    fn spin_off(
+        &mut self,
+        activity: &Activity,
+        symbol: &str,
+        isin: &Option<String>,
+        numerator: &Decimal,
+        denominator: &Decimal,
+        cost_fraction: &Decimal,
+    ) -> Result<(), Error> {
+        // Short seller owes the lender new shares too, which is not tracked
+        if self
+            .shorts
+            .get(&self.key(activity))
+            .is_some_and(|shorts| !shorts.is_empty())
+        {
+            return Err(Error::new(&format!(
+                "{date}: {symbol}: Spin-off of {child} while short position is open is not supported",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                child = symbol,
+            )));
+        }
+
+        let log = self.log;
+        let mut children = vec![];
+        for block in self.get_blocks(self.key(activity)).iter_mut() {
+            let cost = block.cost * *cost_fraction;
+            block.cost = block.cost - cost;
+            children.push(Block {
+                symbol: symbol.to_string(),
+                account: block.account.to_string(),
+                timestamp: block.timestamp,
+                quantity: block.quantity * numerator / denominator,
+                cost,
+                commission: Pln::default(),
+            });
+        }
+
+        if log {
+            println!("{date}: {symbol}: {prefix} child: {child} ratio: {numerator}:{denominator} cost fraction: {cost_fraction} quantity: {quantity} cost: {cost}",
+                date=activity.timestamp.date(),
+                symbol=activity.instrument.symbol,
+                prefix="Spin-off".cyan(),
+                child=symbol,
+                quantity=children.iter().fold(dec!(0), |acc, block| acc + block.quantity),
+                cost=children.iter().fold(Pln::default(), |acc, block| acc + block.cost));
+        }
+
+        let key = (
+            activity.account.to_string(),
+            self.identities.resolve(symbol, isin),
+        );
+        let target = self.get_blocks(key);
+        target.extend(children);
+        target
+            .make_contiguous()
+            .sort_by_key(|block| block.timestamp);
+        Ok(())
+    }
+
+    fn take_selection(&mut self, activity: &Activity) -> Result<Vec<Lot>, Error> {
+        let date = activity.timestamp.date();
+        let position = self
+            .selections
+            .iter()
+            .position(|selection| {
+                selection.symbol == activity.instrument.symbol && selection.date == date
+            })
+            .ok_or(Error::new(&format!(
+                "No lot selection for {} sold on {}",
+                activity.instrument.symbol, date
+            )))?;
+        Ok(self.selections.remove(position).lots)
+    }
+
+    /// Moves lots to the receiving account keeping acquisition dates and cost.
+    fn receive(&mut self, symbol: &str, account: &str, identity: String, blocks: Vec<Block>) {
+        let target = self.get_blocks((account.to_string(), identity));
+        target.extend(blocks.into_iter().map(|mut block| {
+            block.symbol = symbol.to_string();
+            block.account = account.to_string();
+            block
+        }));
+        target
+            .make_contiguous()
+            .sort_by_key(|block| block.timestamp);
+    }
+
+    /// Returns matching method for operations without lot selection, e.g. transfers.
+    fn automatic_method(&self) -> MatchingMethod {
+        match self.method {
+            MatchingMethod::SpecificLot => MatchingMethod::Fifo,
+            method => method,
+        }
+    }
+
+    fn transfer_out(&mut self, activity: &Activity, quantity: &Decimal) -> Result<(), Error> {
+        let method = self.automatic_method();
+
+        let key = self.key(activity);
+        let allocations = match self.blocks.get(&key) {
+            Some(blocks) => Self::allocate(method, blocks, quantity, &[]),
+            None => vec![],
+        };
+        let allocated = allocations
+            .iter()
+            .fold(dec!(0), |acc, (_, quantity)| acc + quantity);
+        if allocated != *quantity {
+            return Err(Error::new(&format!(
+                "{date}: {symbol}: Only {allocated} of {quantity} transferred shares matched open lots in {account}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                account = activity.account,
+            )));
+        }
+
+        let blocks = self.get_blocks(key.clone());
+        let mut moved = vec![];
+        for (index, quantity) in allocations {
+            let block = &mut blocks[index];
+            let (cost, commission) = block.take(quantity);
+            moved.push(Block {
+                symbol: block.symbol.to_string(),
+                account: block.account.to_string(),
+                timestamp: block.timestamp,
+                quantity,
+                cost,
+                commission,
+            });
+        }
+        blocks.retain(|block| block.quantity > dec!(0));
+        moved.sort_by_key(|block| block.timestamp);
+
+        if self.log {
+            println!(
+                "{date}: {symbol}: {prefix} quantity: {quantity} from: {account} cost: {cost}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                prefix = "Transfer out".magenta(),
+                account = activity.account,
+                cost = moved.iter().fold(Pln::default(), |acc, block| acc
+                    + block.cost
+                    + block.commission)
+            );
+        }
+
+        let (_, identity) = key;
+        let pending = self.transfers.entry(identity.clone()).or_default();
+        let position = pending.iter().position(|transfer| {
+            matches!(transfer, Transfer::In { account, quantity: received, .. }
+                if *received == *quantity && *account != activity.account)
+        });
+        match position {
+            Some(position) => {
+                if let Transfer::In {
+                    symbol, account, ..
+                } = pending.remove(position)
+                {
+                    self.receive(&symbol, &account, identity, moved);
+                }
+            }
+            None => pending.push(Transfer::Out {
+                account: activity.account.to_string(),
+                timestamp: activity.timestamp,
+                quantity: *quantity,
+                blocks: moved,
+            }),
+        }
+        Ok(())
+    }
+
+    fn transfer_in(
+        &mut self,
+        activity: &Activity,
+        quantity: &Decimal,
+        lots: &[TransferLot],
+    ) -> Result<(), Error> {
+        if self.log {
+            println!(
+                "{date}: {symbol}: {prefix} quantity: {quantity} to: {account}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                prefix = "Transfer in".magenta(),
+                account = activity.account
+            );
+        }
+
+        let identity = self.identities.key(&activity.instrument);
+        if !lots.is_empty() {
+            if let Some(lot) = lots.iter().find(|lot| lot.quantity <= dec!(0)) {
+                return Err(Error::new(&format!(
+                    "{date}: {symbol}: Transferred lot acquired on {acquired} has no shares",
+                    date = activity.timestamp.date(),
+                    symbol = activity.instrument.symbol,
+                    acquired = lot.date,
+                )));
+            }
+
+            let total = lots.iter().fold(dec!(0), |acc, lot| acc + lot.quantity);
+            if total != *quantity {
+                return Err(Error::new(&format!(
+                    "{date}: {symbol}: Transferred lots quantity {total} differs from transferred {quantity}",
+                    date = activity.timestamp.date(),
+                    symbol = activity.instrument.symbol,
+                )));
+            }
+
+            let blocks = lots
+                .iter()
+                .map(|lot| Block {
+                    symbol: activity.instrument.symbol.to_string(),
+                    account: activity.account.to_string(),
+                    timestamp: lot.date.and_hms_opt(0, 0, 0).unwrap(),
+                    quantity: lot.quantity,
+                    cost: lot.cost,
+                    commission: Pln::default(),
+                })
+                .collect();
+            self.receive(
+                &activity.instrument.symbol,
+                &activity.account,
+                identity,
+                blocks,
+            );
+            return Ok(());
+        }
+
+        let pending = self.transfers.entry(identity.clone()).or_default();
+        let position = pending.iter().position(|transfer| {
+            matches!(transfer, Transfer::Out { account, quantity: sent, .. }
+                if *sent == *quantity && *account != activity.account)
+        });
+        match position {
+            Some(position) => {
+                if let Transfer::Out { blocks, .. } = pending.remove(position) {
+                    self.receive(
+                        &activity.instrument.symbol,
+                        &activity.account,
+                        identity,
+                        blocks,
+                    );
+                }
+            }
+            None => pending.push(Transfer::In {
+                symbol: activity.instrument.symbol.to_string(),
+                account: activity.account.to_string(),
+                timestamp: activity.timestamp,
+                quantity: *quantity,
+            }),
+        }
+        Ok(())
+    }
+
+    fn allocate(
+        method: MatchingMethod,
+        blocks: &VecDeque<Block>,
+        quantity: &Decimal,
+        selection: &[Lot],
+    ) -> Vec<(usize, Decimal)> {
+        let order: Vec<(usize, Option<usize>)> = match method {
+            MatchingMethod::Fifo => (0..blocks.len()).map(|index| (index, None)).collect(),
+            MatchingMethod::Lifo => (0..blocks.len()).rev().map(|index| (index, None)).collect(),
+            MatchingMethod::HighestCost => {
+                let mut order: Vec<_> = (0..blocks.len()).collect();
+                order.sort_by(|a, b| blocks[*b].unit_cost().cmp(&blocks[*a].unit_cost()));
+                order.into_iter().map(|index| (index, None)).collect()
+            }
+            MatchingMethod::SpecificLot => selection
+                .iter()
+                .enumerate()
+                .flat_map(|(lot_index, lot)| {
+                    blocks
+                        .iter()
+                        .enumerate()
+                        .filter(move |(_, block)| block.timestamp.date() == lot.date)
+                        .map(move |(index, _)| (index, Some(lot_index)))
+                })
+                .collect(),
+        };
+
+        let mut available: Vec<Decimal> = blocks.iter().map(|block| block.quantity).collect();
+        let mut selected: Vec<Decimal> = selection.iter().map(|lot| lot.quantity).collect();
+        let mut remaining = *quantity;
+        let mut allocations = vec![];
+
+        for (index, lot_index) in order {
+            let mut quantity = min(available[index], remaining);
+            if let Some(lot_index) = lot_index {
+                quantity = min(quantity, selected[lot_index]);
+                selected[lot_index] -= quantity;
+            }
+
+            if quantity > dec!(0) {
+                available[index] -= quantity;
+                remaining -= quantity;
+                allocations.push((index, quantity));
+            }
+        }
+
+        allocations
     }
 
     fn sell(
@@ -100,71 +1058,266 @@ impl Stock {
         quantity: &Decimal,
         price: &Money,
         commission: &Money,
-    ) -> (Pln, Pln) {
-        let blocks = self.get_blocks(activity.symbol.to_string());
-        let mut sell_quantity = *quantity;
-        let revenue = price.pln * sell_quantity;
-        let sell_commission = commission.pln;
-        let mut cost = Pln::default();
-        let mut buy_commission = Pln::default();
-        let mut logs = vec![];
+    ) -> Result<Option<Sale>, Error> {
+        let selection = match self.method {
+            MatchingMethod::SpecificLot => self.take_selection(activity)?,
+            _ => vec![],
+        };
 
-        while sell_quantity > dec!(0) {
-            let mut block = blocks.pop_front().unwrap();
+        // Writing options and selling futures open short positions
+        let allow_short = self.allow_short || activity.instrument.derivative.is_some();
+        let key = self.key(activity);
+        let empty = VecDeque::new();
+        let blocks = match self.blocks.get(&key) {
+            Some(blocks) => blocks,
+            None if allow_short => &empty,
+            None => {
+                return Err(Error::new(&format!(
+                    "No lots for {} in {}",
+                    activity.instrument.symbol, activity.account
+                )))
+            }
+        };
+        let available = blocks
+            .iter()
+            .fold(dec!(0), |acc, block| acc + block.quantity);
+        let allocations = Self::allocate(self.method, blocks, quantity, &selection);
+        let allocated = allocations
+            .iter()
+            .fold(dec!(0), |acc, (_, quantity)| acc + quantity);
+        let requested = selection
+            .iter()
+            .fold(dec!(0), |acc, lot| acc + lot.quantity);
+        if requested > allocated {
+            return Err(Error::new(&format!(
+                "{date}: {symbol}: Lot selection requests {requested} shares, only {allocated} matched open lots of {dates}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                dates = selection
+                    .iter()
+                    .map(|lot| lot.date.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )));
+        }
 
-            let quantity = min(block.quantity, sell_quantity);
-            block.quantity -= quantity;
-            sell_quantity -= quantity;
+        let short = *quantity - allocated;
+        if short > dec!(0) && !(allow_short && allocated == available) {
+            return Err(Error::new(&format!(
+                "{date}: {symbol}: Only {allocated} of {quantity} sold shares matched open lots using {method} method{hint}",
+                date = activity.timestamp.date(),
+                symbol = activity.instrument.symbol,
+                method = self.method,
+                hint = match allocated == available {
+                    true => ", use --allow-short to open short positions",
+                    false => "",
+                },
+            )));
+        }
 
-            let block_cost = block.price * quantity;
-            let block_buy_commission = if block.quantity == dec!(0) {
-                block.commission
-            } else {
-                Pln::default()
+        let (increase, decrease) =
+            self.take_premiums(activity, &[(Right::Put, false)], &[(Right::Call, true)]);
+        let short_commission = commission.pln * (short / *quantity);
+        if short > dec!(0) {
+            // Premium of an option assigned into a short position adjusts its cost once closed
+            let adjustment = match allocated == dec!(0) {
+                true => increase - decrease,
+                false => Pln::default(),
             };
+            self.open_short(activity, &short, price, short_commission + adjustment);
+        }
+        if allocated == dec!(0) {
+            return Ok(None);
+        }
 
-            let log = format!("  {date}: Sell block quantity: {quantity} cost: {cost} price: {price} commission: {commission}",
-                date=block.timestamp.date(),
-                cost=block_cost,
-                price=block.price,
-                commission=block_buy_commission,
-            );
-            logs.push(log);
+        let quantity = &allocated;
+        let revenue = price.pln * (*quantity * activity.instrument.multiplier());
+        let blocks = self.get_blocks(key);
+        let sell_commission = commission.pln - short_commission;
+        let mut cost = Pln::default();
+        let mut buy_commission = Pln::default();
+        let mut lots = vec![];
+
+        for (index, quantity) in allocations {
+            let block = &mut blocks[index];
+            let price = block.price();
+            let (block_cost, block_buy_commission) = block.take(quantity);
+
+            lots.push(LotMatch {
+                timestamp: block.timestamp,
+                quantity,
+                price,
+                cost: block_cost,
+                commission: block_buy_commission,
+            });
 
             cost += block_cost;
             buy_commission += block_buy_commission;
+        }
+
+        blocks.retain(|block| block.quantity > dec!(0));
 
-            if block.quantity > dec!(0) {
-                blocks.push_front(block);
+        let adjustment = increase - decrease;
+        let cost = cost + buy_commission + sell_commission + adjustment;
+        let value = revenue - cost;
+        if self.log {
+            let (income, loss) = if value > Pln::new(0) {
+                (value, Pln::default())
+            } else {
+                (Pln::default(), value.abs())
+            };
+
+            println!("{date}: {symbol}: {prefix} quantity: {quantity} cost: {cost} revenue: {revenue} income: {income} loss: {loss} price: {price_pln} ({price_org}) commission: {commission_pln} ({commission_org})",
+                date=activity.timestamp.date(),
+                symbol=activity.instrument.symbol,
+                prefix="Sell".red(),
+                price_pln=price.pln,
+                price_org=price.original,
+                commission_pln=sell_commission,
+                commission_org=commission.original);
+
+            for lot in &lots {
+                println!("  {date}: Sell block quantity: {quantity} cost: {cost} price: {price} commission: {commission}",
+                    date=lot.timestamp.date(),
+                    quantity=lot.quantity,
+                    cost=lot.cost,
+                    price=lot.price,
+                    commission=lot.commission,
+                );
             }
         }
 
-        let cost = cost + buy_commission + sell_commission;
+        Ok(Some(Sale {
+            symbol: activity.instrument.symbol.to_string(),
+            timestamp: activity.timestamp,
+            quantity: *quantity,
+            revenue,
+            cost,
+            commission: sell_commission,
+            lots,
+            short: false,
+            derivative: activity.instrument.derivative.is_some(),
+            adjustment,
+        }))
+    }
+
+    /// Opens short lot holding sale proceeds as its cost; income is realized when it is closed.
+    fn open_short(
+        &mut self,
+        activity: &Activity,
+        quantity: &Decimal,
+        price: &Money,
+        commission: Pln,
+    ) {
+        let proceeds = price.pln * (*quantity * activity.instrument.multiplier());
+        if self.log {
+            println!("{date}: {symbol}: {prefix} quantity: {quantity} proceeds: {proceeds} price: {price_pln} ({price_org}) commission: {commission}",
+                date=activity.timestamp.date(),
+                symbol=activity.instrument.symbol,
+                prefix="Short sell".red(),
+                price_pln=price.pln,
+                price_org=price.original);
+        }
+
+        *self.deferred.entry(activity.timestamp.year()).or_default() += proceeds;
+        self.shorts
+            .entry(self.key(activity))
+            .or_default()
+            .push_back(Block {
+                symbol: activity.instrument.symbol.to_string(),
+                account: activity.account.to_string(),
+                timestamp: activity.timestamp,
+                quantity: *quantity,
+                cost: proceeds,
+                commission,
+            });
+    }
+
+    /// Closes short lots with a buy, returns the realized sale.
+    fn close_short(
+        &mut self,
+        activity: &Activity,
+        quantity: &Decimal,
+        price: &Money,
+        commission: &Money,
+    ) -> Option<Sale> {
+        let method = self.automatic_method();
+        let shorts = self.shorts.get_mut(&self.key(activity))?;
+        let allocations = Self::allocate(method, shorts, quantity, &[]);
+        let closed = allocations
+            .iter()
+            .fold(dec!(0), |acc, (_, quantity)| acc + quantity);
+        if closed == dec!(0) {
+            return None;
+        }
+
+        let buy_commission = commission.pln * (closed / *quantity);
+        let mut revenue = Pln::default();
+        let mut sell_commission = Pln::default();
+        let mut lots = vec![];
+        for (index, quantity) in allocations {
+            let block = &mut shorts[index];
+            let price = block.price();
+            let (proceeds, commission) = block.take(quantity);
+
+            lots.push(LotMatch {
+                timestamp: block.timestamp,
+                quantity,
+                price,
+                cost: proceeds,
+                commission,
+            });
+
+            revenue += proceeds;
+            sell_commission += commission;
+        }
+        shorts.retain(|block| block.quantity > dec!(0));
+
+        let cost = price.pln * (closed * activity.instrument.multiplier())
+            + buy_commission
+            + sell_commission;
         let value = revenue - cost;
-        let (income, loss) = if value > Pln::new(0) {
-            (value, Pln::default())
-        } else {
-            (Pln::default(), value.abs())
-        };
+        if self.log {
+            let (income, loss) = if value > Pln::new(0) {
+                (value, Pln::default())
+            } else {
+                (Pln::default(), value.abs())
+            };
 
-        println!("{date}: {symbol}: {prefix} quantity: {quantity} cost: {cost} revenue: {revenue} income: {income} loss: {loss} price: {price_pln} ({price_org}) commission: {commission_pln} ({commission_org})",
-            date=activity.timestamp.date(),
-            symbol=activity.symbol,
-            prefix="Sell".red(),
-            price_pln=price.pln,
-            price_org=price.original,
-            commission_pln=commission.pln,
-            commission_org=commission.original);
+            println!("{date}: {symbol}: {prefix} quantity: {closed} cost: {cost} revenue: {revenue} income: {income} loss: {loss} price: {price_pln} ({price_org}) commission: {buy_commission}",
+                date=activity.timestamp.date(),
+                symbol=activity.instrument.symbol,
+                prefix="Short close".red(),
+                price_pln=price.pln,
+                price_org=price.original);
 
-        for log in logs {
-            println!("{}", log);
+            for lot in &lots {
+                println!("  {date}: Short block quantity: {quantity} proceeds: {proceeds} price: {price} commission: {commission}",
+                    date=lot.timestamp.date(),
+                    quantity=lot.quantity,
+                    proceeds=lot.cost,
+                    price=lot.price,
+                    commission=lot.commission,
+                );
+            }
         }
 
-        (revenue, cost)
+        Some(Sale {
+            symbol: activity.instrument.symbol.to_string(),
+            timestamp: activity.timestamp,
+            quantity: closed,
+            revenue,
+            cost,
+            commission: buy_commission,
+            lots,
+            short: true,
+            derivative: activity.instrument.derivative.is_some(),
+            adjustment: Pln::default(),
+        })
     }
 }
 
-fn load_activities(path: &String) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+pub fn load_activities(path: &String) -> Result<Vec<Activity>, Box<dyn error::Error>> {
     let mut activities = vec![];
     for file_path in glob(path)? {
         let file_path = file_path?;
@@ -178,108 +1331,1160 @@ fn load_activities(path: &String) -> Result<Vec<Activity>, Box<dyn error::Error>
     Ok(activities)
 }
 
-fn process_dividend(activity: &Activity, value: &Money, withholding_tax: &Money) -> (Pln, Pln) {
+fn load_lot_selections(path: &Option<String>) -> Result<Vec<LotSelection>, Box<dyn error::Error>> {
+    match path {
+        Some(path) => {
+            let file = OpenOptions::new().read(true).open(path)?;
+            let reader = BufReader::new(file);
+            Ok(serde_json::from_reader(reader)?)
+        }
+        None => Ok(vec![]),
+    }
+}
+
+pub fn load_losses(path: &Option<String>) -> Result<Vec<Loss>, Box<dyn error::Error>> {
+    match path {
+        Some(path) => {
+            let file = OpenOptions::new().read(true).open(path)?;
+            let reader = BufReader::new(file);
+            Ok(serde_json::from_reader(reader)?)
+        }
+        None => Ok(vec![]),
+    }
+}
+
+pub(crate) fn compute_pit38(ledger: &mut Ledger, year: i32, tax_return: &TaxReturn) -> Pit38 {
+    let value = tax_return.stock_revenue - tax_return.stock_cost;
+    let mut pit38 = Pit38 {
+        stock_revenue: tax_return.stock_revenue,
+        stock_cost: tax_return.stock_cost,
+        derivative_revenue: tax_return.derivative_revenue,
+        derivative_cost: tax_return.derivative_cost,
+        ..Default::default()
+    };
+
+    if value > Pln::new(0) {
+        pit38.stock_income = value;
+        pit38.loss_deduction = ledger.deduct(year, value);
+    } else {
+        pit38.stock_loss = value.abs();
+        ledger.add(year, pit38.stock_loss);
+    }
+
+    pit38.tax_base = pit38.stock_income - pit38.loss_deduction;
+    pit38.stock_tax = pit38.tax_base * Tax::new(19);
+    ledger.warn(year);
+    pit38
+}
+
+/// Withholding tax adjustment posted later is added to the tax withheld on the dividend
+/// before the creditable part is limited.
+fn process_dividend(
+    activity: &Activity,
+    value: &Money,
+    withholding_tax: &Money,
+    adjustment: Pln,
+    country: &Option<String>,
+    treaties: &Treaties,
+) -> (Pln, Pln, Pln) {
+    let withheld = withholding_tax.pln + adjustment;
+    let creditable_tax = treaties.creditable(country, value.pln, withheld);
+
     println!(
-        "{date}: {symbol}: {prefix} value: {value} / {value_pln}: tax: {tax} / {tax_pln}",
+        "{date}: {symbol}: {prefix} value: {value} / {value_pln}: tax: {tax} / {tax_pln} creditable: {creditable_tax} country: {country}",
         date = activity.timestamp.date(),
-        symbol = activity.symbol,
+        symbol = activity.instrument.symbol,
         prefix = "Dividend".yellow(),
         value = value.original,
         value_pln = value.pln,
         tax = withholding_tax.original,
         tax_pln = withholding_tax.pln,
+        country = country.as_deref().unwrap_or("<unknown>"),
+    );
+    (value.pln, withheld, creditable_tax)
+}
+
+/// Returns country the dividend was taxed in, falls back to the country prefix of the ISIN.
+pub fn dividend_country(activity: &Activity, country: &Option<String>) -> Option<String> {
+    country.clone().or_else(|| {
+        let isin = activity.instrument.isin.as_ref()?;
+        isin.get(..2)
+            .filter(|code| code.chars().all(|c| c.is_ascii_alphabetic()))
+            .map(str::to_string)
+    })
+}
+
+/// Reports withholding tax adjustment, flags the ones posted after the end of the tax year.
+fn process_withholding_adjustment(activity: &Activity, value: &Money, year: i32) {
+    println!(
+        "{date}: {symbol}: {prefix} value: {value} / {value_pln} dividend: {dividend_date}",
+        date = activity.timestamp.date(),
+        symbol = activity.instrument.symbol,
+        prefix = "Withholding adjustment".yellow(),
+        value = value.original,
+        value_pln = value.pln,
+        dividend_date = dividend_date(activity),
+    );
+    if is_amendment(activity, year) {
+        println!("{year}: {symbol} {prefix} withholding tax on dividend paid on {dividend_date} adjusted by {value} on {date}, return filed for {year} should be corrected",
+            symbol = activity.instrument.symbol,
+            prefix = "AMENDMENT".yellow(),
+            dividend_date = dividend_date(activity),
+            value = value.pln,
+            date = activity.timestamp.date());
+    }
+}
+
+/// Returns whether adjustment was posted after the end of the tax year of its dividend.
+fn is_amendment(activity: &Activity, year: i32) -> bool {
+    activity.timestamp.year() > year
+}
+
+fn dividend_date(activity: &Activity) -> NaiveDate {
+    match &activity.operation {
+        Operation::WithholdingAdjustment {
+            dividend_date: Some(date),
+            ..
+        } => *date,
+        _ => activity.timestamp.date(),
+    }
+}
+
+/// Returns year of the tax return, withholding adjustments belong to the year of the dividend.
+fn tax_year(activity: &Activity) -> i32 {
+    dividend_date(activity).year()
+}
+
+/// Links withholding tax adjustments without a dividend date to the latest earlier dividend
+/// of the same instrument in the same account.
+fn link_adjustments(activities: &mut [Activity], stock: &Stock) {
+    let dividends: Vec<_> = activities
+        .iter()
+        .filter(|activity| {
+            matches!(
+                activity.operation,
+                Operation::Dividend { .. } | Operation::PaymentInLieu { .. }
+            )
+        })
+        .map(|activity| (stock.key(activity), activity.timestamp))
+        .collect();
+
+    for activity in activities.iter_mut() {
+        let key = stock.key(activity);
+        let Operation::WithholdingAdjustment { dividend_date, .. } = &mut activity.operation else {
+            continue;
+        };
+        if dividend_date.is_some() {
+            continue;
+        }
+
+        *dividend_date = dividends
+            .iter()
+            .filter(|(dividend, timestamp)| *dividend == key && *timestamp <= activity.timestamp)
+            .map(|(_, timestamp)| timestamp.date())
+            .max();
+        if dividend_date.is_none() {
+            println!(
+                "{prefix} no dividend of {symbol} in {account} found for withholding tax adjustment on {date}",
+                prefix = "WARNING".yellow(),
+                symbol = activity.instrument.symbol,
+                account = activity.account,
+                date = activity.timestamp.date(),
+            );
+        }
+    }
+}
+
+/// Reports currency conversion with difference between PLN values of bought and sold amounts.
+fn process_forex(activity: &Activity, sold: &Money, bought: &Money, commission: &Money) {
+    println!(
+        "{date}: {symbol}: {prefix} sold: {sold} / {sold_pln} bought: {bought} / {bought_pln} commission: {commission} / {commission_pln} difference: {difference} rate: {rate}",
+        date = activity.timestamp.date(),
+        symbol = activity.instrument.symbol,
+        prefix = "Forex".blue(),
+        sold = sold.original,
+        sold_pln = sold.pln,
+        bought = bought.original,
+        bought_pln = bought.pln,
+        commission = commission.original,
+        commission_pln = commission.pln,
+        rate = sold
+            .rate
+            .as_ref()
+            .or(bought.rate.as_ref())
+            .map_or("-".to_string(), |rate| rate.to_string()),
+        difference = bought.pln - sold.pln,
+    );
+}
+
+/// Reports received interest, returns its value, withholding tax and creditable part of it.
+fn process_interest(
+    activity: &Activity,
+    value: &Money,
+    withholding_tax: &Money,
+) -> (Pln, Pln, Pln) {
+    let limit = value.pln * Tax::new(19);
+    let creditable_tax = if withholding_tax.pln < limit {
+        withholding_tax.pln
+    } else {
+        limit
+    };
+
+    println!(
+        "{date}: {symbol}: {prefix} value: {value} / {value_pln}: tax: {tax} / {tax_pln} creditable: {creditable_tax}",
+        date = activity.timestamp.date(),
+        symbol = activity.instrument.symbol,
+        prefix = "Interest".yellow(),
+        value = value.original,
+        value_pln = value.pln,
+        tax = withholding_tax.original,
+        tax_pln = withholding_tax.pln,
+    );
+    (value.pln, withholding_tax.pln, creditable_tax)
+}
+
+fn process_fee(activity: &Activity, value: &Money, kind: &FeeKind, description: &str) {
+    println!(
+        "{date}: {prefix} {description}: {value} / {value_pln} kind: {kind:?} {deductible}",
+        date = activity.timestamp.date(),
+        prefix = "Fee".yellow(),
+        value = value.original,
+        value_pln = value.pln,
+        deductible = match kind.is_deductible() {
+            true => "deductible",
+            false => "not deductible",
+        },
     );
-    (value.pln, withholding_tax.pln)
 }
 
 fn process_annual_activities<'a>(
     stock: &mut Stock,
+    ledger: &mut Ledger,
+    treaties: &Treaties,
     year: i32,
     activities: impl Iterator<Item = &'a Activity>,
-) {
+) -> Result<TaxReturn, Box<dyn error::Error>> {
+    let activities: Vec<_> = activities.collect();
     let mut tax_positions = HashMap::<&str, TaxPosition>::new();
+    let mut sales = vec![];
+    let mut conversions = 0;
+    let mut forex_sold = Pln::default();
+    let mut forex_bought = Pln::default();
+    let mut forex_commission = Pln::default();
+    let mut interest = Pln::default();
+    let mut interest_withholding_tax = Pln::default();
+    let mut interest_creditable_tax = Pln::default();
+    let mut interest_paid = Pln::default();
+    let mut deductible_fees = Pln::default();
+    let mut other_fees = Pln::default();
 
-    for activity in activities {
+    let mut adjustments = HashMap::<((String, String), NaiveDate), (&str, Pln)>::new();
+    for activity in activities.iter().copied() {
+        if let Operation::WithholdingAdjustment { value, .. } = &activity.operation {
+            process_withholding_adjustment(activity, value, year);
+            adjustments
+                .entry((stock.key(activity), dividend_date(activity)))
+                .or_insert((&activity.instrument.symbol, Pln::default()))
+                .1 += value.pln;
+        }
+    }
+
+    for activity in activities.iter().copied() {
         match &activity.operation {
+            Operation::WithholdingAdjustment { .. } => {}
+            Operation::Interest {
+                value,
+                withholding_tax,
+            } => {
+                let (value, withholding_tax, creditable_tax) =
+                    process_interest(activity, value, withholding_tax);
+                interest += value;
+                interest_withholding_tax += withholding_tax;
+                interest_creditable_tax += creditable_tax;
+            }
+            Operation::InterestPaid { value } => {
+                println!(
+                    "{date}: {symbol}: {prefix} value: {value} / {value_pln}",
+                    date = activity.timestamp.date(),
+                    symbol = activity.instrument.symbol,
+                    prefix = "Interest paid".yellow(),
+                    value = value.original,
+                    value_pln = value.pln,
+                );
+                interest_paid += value.pln;
+            }
+            Operation::Fee {
+                value,
+                kind,
+                description,
+            } => {
+                process_fee(activity, value, kind, description);
+                match kind.is_deductible() {
+                    true => deductible_fees += value.pln,
+                    false => other_fees += value.pln,
+                }
+            }
+            Operation::Forex {
+                sold,
+                bought,
+                commission,
+            } => {
+                process_forex(activity, sold, bought, commission);
+                conversions += 1;
+                forex_sold += sold.pln;
+                forex_bought += bought.pln;
+                forex_commission += commission.pln;
+            }
             Operation::Dividend {
                 value,
                 withholding_tax,
+                country,
             } => {
-                let (dividend, withholding_tax) =
-                    process_dividend(activity, value, withholding_tax);
-                let tax_position = tax_positions.entry(&activity.symbol).or_default();
+                let country = dividend_country(activity, country);
+                let adjustment = adjustments
+                    .remove(&(stock.key(activity), activity.timestamp.date()))
+                    .map(|(_, adjustment)| adjustment)
+                    .unwrap_or_default();
+                let (dividend, withholding_tax, creditable_tax) = process_dividend(
+                    activity,
+                    value,
+                    withholding_tax,
+                    adjustment,
+                    &country,
+                    treaties,
+                );
+                let tax_position = tax_positions
+                    .entry(&activity.instrument.symbol)
+                    .or_default();
                 tax_position.dividend += dividend;
                 tax_position.dividend_withholding_tax += withholding_tax;
+                tax_position.dividend_creditable_tax += creditable_tax;
             }
-            Operation::Buy {
-                quantity,
-                price,
-                commission,
+            Operation::PaymentInLieu {
+                value,
+                withholding_tax,
             } => {
-                stock.buy(activity, quantity, price, commission);
+                let adjustment = adjustments
+                    .remove(&(stock.key(activity), activity.timestamp.date()))
+                    .map(|(_, adjustment)| adjustment)
+                    .unwrap_or_default();
+                let withheld = withholding_tax.pln + adjustment;
+                println!(
+                    "{date}: {symbol}: {prefix} value: {value} / {value_pln}: tax: {tax} / {tax_pln} creditable: {creditable_tax}",
+                    date = activity.timestamp.date(),
+                    symbol = activity.instrument.symbol,
+                    prefix = "Payment in lieu".yellow(),
+                    value = value.original,
+                    value_pln = value.pln,
+                    tax = withholding_tax.original,
+                    tax_pln = withholding_tax.pln,
+                    creditable_tax = Pln::default(),
+                );
+                // Substitute payments are not dividends under tax treaties
+                let tax_position = tax_positions
+                    .entry(&activity.instrument.symbol)
+                    .or_default();
+                tax_position.dividend += value.pln;
+                tax_position.dividend_withholding_tax += withheld;
             }
-            Operation::Sell {
-                quantity,
-                price,
-                commission,
-            } => {
-                let (revenue, cost) = stock.sell(activity, quantity, price, commission);
-                let tax_position = tax_positions.entry(&activity.symbol).or_default();
-                tax_position.stock_revenue += revenue;
-                tax_position.stock_cost += cost;
+            _ => {
+                if let Some(sale) = stock.apply(activity)? {
+                    let tax_position = tax_positions
+                        .entry(&activity.instrument.symbol)
+                        .or_default();
+                    tax_position.stock_revenue += sale.revenue;
+                    tax_position.stock_cost += sale.cost;
+                    tax_position.derivative = sale.derivative;
+                    sales.push(sale);
+                }
             }
         }
     }
 
+    // Adjustments of dividends missing from the year are not creditable
+    for ((_, date), (symbol, adjustment)) in adjustments {
+        println!(
+            "{year}: {symbol} {prefix} withholding tax adjustment {adjustment} of dividend paid on {date} has no matching dividend",
+            prefix = "WARNING".yellow(),
+        );
+        tax_positions
+            .entry(symbol)
+            .or_default()
+            .dividend_withholding_tax += adjustment;
+    }
+
     let tax_return = tax_positions.iter().map(|(symbol, tax_position)|{
-        let dividend_tax = tax_position.dividend * Tax::new(19) - tax_position.dividend_withholding_tax;
+        let dividend_tax = tax_position.dividend * Tax::new(19) - tax_position.dividend_creditable_tax;
+        let dividend_tax = if dividend_tax > Pln::new(0) { dividend_tax } else { Pln::default() };
+        let dividend_excess_tax = tax_position.dividend_withholding_tax - tax_position.dividend_creditable_tax;
         let value = tax_position.stock_revenue - tax_position.stock_cost;
         let (stock_income, stock_loss) = if value > Pln::new(0) {
             (value, Pln::default())
         } else {
             (Pln::default(), value.abs())
         };
-        let stock_tax = stock_income * Tax::new(19);
+        let (derivative_revenue, derivative_cost) = match tax_position.derivative {
+            true => (tax_position.stock_revenue, tax_position.stock_cost),
+            false => (Pln::default(), Pln::default()),
+        };
 
-        println!("{year}: {symbol} dividend tax: {dividend_tax} stock income: {stock_income} stock loss: {stock_loss} stock_tax: {stock_tax}");
+        println!("{year}: {symbol} dividend tax: {dividend_tax} stock income: {stock_income} stock loss: {stock_loss}");
+        if dividend_excess_tax > Pln::new(0) {
+            println!("{year}: {symbol} {prefix} excess withholding tax: {dividend_excess_tax} should be reclaimed from the foreign tax office",
+                prefix="RECLAIM".yellow());
+        }
 
         TaxReturn {
-            dividend_tax: dividend_tax,
+            dividend_tax,
+            dividend_excess_tax,
             stock_revenue: tax_position.stock_revenue,
             stock_cost: tax_position.stock_cost,
-            stock_income: stock_income,
-            stock_loss: stock_loss,
-            stock_tax: stock_tax,
+            stock_income,
+            stock_loss,
+            derivative_revenue,
+            derivative_cost,
         }
     }).fold(TaxReturn::default(), |mut acc, a| { acc +=a; acc });
 
-    let summary = format!("{year}: {prefix} dividend tax: {dividend_tax} stock revenue: {stock_revenue} stock cost: {stock_cost} stock income: {stock_income} stock loss: {stock_loss} stock tax: {stock_tax}",
+    let summary = format!("{year}: {prefix} dividend tax: {dividend_tax} dividend excess withholding tax: {dividend_excess_tax} stock revenue: {stock_revenue} stock cost: {stock_cost} stock income: {stock_income} stock loss: {stock_loss} matching: {matching}",
         prefix="TAX RETURN".bright_blue(),
-        dividend_tax=tax_return.dividend_tax * Tax::new(19),
+        matching=stock.method,
+        dividend_tax=tax_return.dividend_tax,
+        dividend_excess_tax=tax_return.dividend_excess_tax,
         stock_revenue=tax_return.stock_revenue,
         stock_cost=tax_return.stock_cost,
         stock_income=tax_return.stock_income,
         stock_loss=tax_return.stock_loss,
-        stock_tax=tax_return.stock_tax,
     );
     println!("{}", summary.bold());
+
+    let pit38 = compute_pit38(ledger, year, &tax_return);
+    let summary = format!("{year}: {prefix} stock revenue: {stock_revenue} stock cost: {stock_cost} stock income: {stock_income} stock loss: {stock_loss} loss deduction: {loss_deduction} tax base: {tax_base} stock tax: {stock_tax} loss strategy: {strategy}",
+        prefix="PIT-38".bright_blue(),
+        stock_revenue=pit38.stock_revenue,
+        stock_cost=pit38.stock_cost,
+        stock_income=pit38.stock_income,
+        stock_loss=pit38.stock_loss,
+        loss_deduction=pit38.loss_deduction,
+        tax_base=pit38.tax_base,
+        stock_tax=pit38.stock_tax,
+        strategy=ledger.strategy(),
+    );
+    println!("{}", summary.bold());
+    if pit38.derivative_revenue != Pln::default() || pit38.derivative_cost != Pln::default() {
+        println!("{year}: {prefix} other revenue: {revenue} other cost: {cost} (included in the totals above)",
+            prefix="PIT-38 DERIVATIVES".bright_blue(),
+            revenue=pit38.derivative_revenue,
+            cost=pit38.derivative_cost);
+    }
+    if conversions > 0 {
+        println!("{year}: {prefix} conversions: {conversions} sold: {forex_sold} bought: {forex_bought} commission: {forex_commission} difference: {difference} (not included in the tax return)",
+            prefix="FOREX".bright_blue(),
+            difference=forex_bought - forex_sold);
+    }
+    if interest != Pln::default() || interest_paid != Pln::default() {
+        let interest_tax = interest * Tax::new(19) - interest_creditable_tax;
+        println!("{year}: {prefix} income: {interest} withholding tax: {interest_withholding_tax} creditable: {interest_creditable_tax} tax: {interest_tax} paid: {interest_paid} (paid interest is not deductible)",
+            prefix="INTEREST".bright_blue());
+    }
+    if deductible_fees != Pln::default() || other_fees != Pln::default() {
+        println!("{year}: {prefix} deductible: {deductible_fees} not deductible: {other_fees} (deductible fees are not included in PIT-38 costs above)",
+            prefix="FEES".bright_blue());
+    }
+
+    reconcile::check(
+        year,
+        &activities,
+        &sales,
+        stock.deferred(year),
+        &stock.identities,
+        treaties,
+        &reconcile::Reported {
+            tax_positions: &tax_positions,
+            tax_return: &tax_return,
+            pit38: &pit38,
+        },
+    )?;
+
+    Ok(tax_return)
+}
+
+fn create_stock(
+    args: &MatchingArgs,
+    activities: &[Activity],
+    log: bool,
+) -> Result<Stock, Box<dyn error::Error>> {
+    let selections = load_lot_selections(&args.lots)?;
+    if args.lots.is_some() && args.matching != MatchingMethod::SpecificLot {
+        return Err(Error::new("Lot selection file requires specific lot matching").into());
+    }
+
+    Ok(Stock::new(
+        args.matching,
+        selections,
+        Identities::new(activities),
+        args.allow_short,
+        log,
+    ))
+}
+
+/// Replays all activities without reporting, returns open lots and all realized sales.
+pub fn replay(
+    args: &MatchingArgs,
+    activities: &[Activity],
+) -> Result<(Stock, Vec<Sale>), Box<dyn error::Error>> {
+    let mut stock = create_stock(args, activities, false)?;
+    let mut sales = vec![];
+    for activity in activities {
+        if let Some(sale) = stock.apply(activity)? {
+            sales.push(sale);
+        }
+    }
+    Ok((stock, sales))
 }
 
 pub fn command(args: &CommandArgs) -> Result<(), Box<dyn error::Error>> {
-    let mut stock = Stock::default();
-    let activities = load_activities(&args.path)?;
+    let mut activities = load_activities(&args.path)?;
+    let mut stock = create_stock(&args.matching, &activities, true)?;
+    link_adjustments(&mut activities, &stock);
+    let mut ledger = Ledger::new(args.loss_strategy, load_losses(&args.losses)?);
+    let treaties = Treaties::load(&args.treaty)?;
 
-    let years = activities.iter().map(|a| a.timestamp.year());
+    let years = activities.iter().map(tax_year);
     let mut years: Vec<_> = HashSet::<i32>::from_iter(years).into_iter().collect();
     years.sort();
 
     for year in years {
         let activities = activities
             .iter()
-            .filter(|a| a.timestamp.year() == year)
+            .filter(|a| tax_year(a) == year)
             .into_iter();
-        process_annual_activities(&mut stock, year, activities);
+        process_annual_activities(&mut stock, &mut ledger, &treaties, year, activities)?;
     }
 
+    stock.verify_transfers()?;
+    stock.verify_assignments()?;
+    stock.verify_selections()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::{Derivative, DerivativeKind};
+    use chrono::NaiveDate;
+
+    fn fifo_stock() -> Stock {
+        Stock::new(
+            MatchingMethod::Fifo,
+            vec![],
+            Identities::default(),
+            false,
+            false,
+        )
+    }
+
+    fn money(value: Decimal) -> Money {
+        Money {
+            original: Pln::new_box(value),
+            pln: Pln::new(value),
+            rate: None,
+        }
+    }
+
+    fn activity(account: &str, day: u32, operation: Operation) -> Activity {
+        Activity {
+            instrument: Instrument::new("CDR"),
+            account: account.to_string(),
+            timestamp: NaiveDate::from_ymd_opt(2022, 1, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            operation,
+        }
+    }
+
+    #[test]
+    fn test_transfer_keeps_lots() {
+        let activities = [
+            activity(
+                "mbank",
+                1,
+                Operation::Buy {
+                    quantity: dec!(10),
+                    price: money(dec!(300)),
+                    commission: money(dec!(5)),
+                },
+            ),
+            activity(
+                "ib",
+                2,
+                Operation::TransferIn {
+                    quantity: dec!(10),
+                    lots: vec![],
+                },
+            ),
+            activity("mbank", 3, Operation::TransferOut { quantity: dec!(10) }),
+            activity(
+                "ib",
+                4,
+                Operation::Sell {
+                    quantity: dec!(10),
+                    price: money(dec!(400)),
+                    commission: money(dec!(1)),
+                },
+            ),
+        ];
+
+        let mut stock = fifo_stock();
+        let sales: Vec<_> = activities
+            .iter()
+            .filter_map(|activity| stock.apply(activity).unwrap())
+            .collect();
+
+        assert!(stock.verify_transfers().is_ok());
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].cost, Pln::new(3006));
+        assert_eq!(
+            sales[0].lots[0].timestamp.date(),
+            NaiveDate::from_ymd_opt(2022, 1, 1).unwrap()
+        );
+
+        let mut stock = fifo_stock();
+        stock.apply(&activities[0]).unwrap();
+        stock.apply(&activities[2]).unwrap();
+        assert!(stock.verify_transfers().is_err());
+        assert!(stock.apply(&activities[3]).is_err());
+    }
+
+    fn lots_sold(method: MatchingMethod, selections: Vec<LotSelection>) -> Result<Sale, Error> {
+        let buy = |day, quantity, price| {
+            activity(
+                "mbank",
+                day,
+                Operation::Buy {
+                    quantity,
+                    price: money(price),
+                    commission: money(dec!(1)),
+                },
+            )
+        };
+        let activities = [
+            buy(1, dec!(10), dec!(100)),
+            buy(2, dec!(10), dec!(300)),
+            buy(3, dec!(10), dec!(200)),
+            activity(
+                "mbank",
+                4,
+                Operation::Sell {
+                    quantity: dec!(15),
+                    price: money(dec!(400)),
+                    commission: money(dec!(0)),
+                },
+            ),
+        ];
+
+        let mut stock = Stock {
+            method,
+            selections,
+            ..fifo_stock()
+        };
+        let mut sales = vec![];
+        for activity in &activities {
+            sales.extend(stock.apply(activity)?);
+        }
+        stock.verify_selections()?;
+        Ok(sales.remove(0))
+    }
+
+    fn lot_days(sale: &Sale) -> Vec<(u32, Decimal)> {
+        sale.lots
+            .iter()
+            .map(|lot| (lot.timestamp.day(), lot.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn test_lifo() {
+        let sale = lots_sold(MatchingMethod::Lifo, vec![]).unwrap();
+        assert_eq!(lot_days(&sale), vec![(3, dec!(10)), (2, dec!(5))]);
+        // Commission of the fully consumed lot only
+        assert_eq!(sale.cost, Pln::new(3501));
+    }
+
+    #[test]
+    fn test_highest_cost() {
+        let sale = lots_sold(MatchingMethod::HighestCost, vec![]).unwrap();
+        assert_eq!(lot_days(&sale), vec![(2, dec!(10)), (3, dec!(5))]);
+        assert_eq!(sale.cost, Pln::new(4001));
+
+        // Empty lots have no cost per unit to order by and are rejected when transferred in
+        let transfer = activity(
+            "ib",
+            1,
+            Operation::TransferIn {
+                quantity: dec!(10),
+                lots: vec![
+                    TransferLot {
+                        date: NaiveDate::from_ymd_opt(2021, 1, 1).unwrap(),
+                        quantity: dec!(10),
+                        cost: Pln::new(1000),
+                    },
+                    TransferLot {
+                        date: NaiveDate::from_ymd_opt(2021, 2, 1).unwrap(),
+                        quantity: dec!(0),
+                        cost: Pln::new(1),
+                    },
+                ],
+            },
+        );
+        let mut stock = Stock {
+            method: MatchingMethod::HighestCost,
+            ..fifo_stock()
+        };
+        assert!(stock.apply(&transfer).is_err());
+    }
+
+    #[test]
+    fn test_specific_lot() {
+        let date = |day| NaiveDate::from_ymd_opt(2022, 1, day).unwrap();
+        let selection = |lots: &[(u32, Decimal)]| {
+            vec![LotSelection {
+                symbol: "CDR".to_string(),
+                date: date(4),
+                lots: lots
+                    .iter()
+                    .map(|(day, quantity)| Lot {
+                        date: date(*day),
+                        quantity: *quantity,
+                    })
+                    .collect(),
+            }]
+        };
+
+        let sale = lots_sold(
+            MatchingMethod::SpecificLot,
+            selection(&[(1, dec!(5)), (3, dec!(10))]),
+        )
+        .unwrap();
+        assert_eq!(lot_days(&sale), vec![(1, dec!(5)), (3, dec!(10))]);
+        assert_eq!(sale.cost, Pln::new(2501));
+
+        // Requested quantity exceeding the sale is reported instead of ignored
+        assert!(lots_sold(
+            MatchingMethod::SpecificLot,
+            selection(&[(1, dec!(10)), (3, dec!(10))]),
+        )
+        .is_err());
+
+        let mut selections = selection(&[(1, dec!(5)), (3, dec!(10))]);
+        selections.extend(selection(&[(2, dec!(1))]).into_iter().map(|mut unused| {
+            unused.date = date(5);
+            unused
+        }));
+        assert!(lots_sold(MatchingMethod::SpecificLot, selections).is_err());
+    }
+
+    fn option(right: Right) -> Instrument {
+        Instrument {
+            symbol: format!("CDR {:?}", right),
+            derivative: Some(Derivative {
+                kind: DerivativeKind::Option,
+                underlying: "CDR".to_string(),
+                multiplier: dec!(100),
+                expiry: NaiveDate::from_ymd_opt(2022, 1, 21),
+                strike: Some(dec!(50)),
+                right: Some(right),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn option_activity(right: Right, day: u32, operation: Operation) -> Activity {
+        Activity {
+            instrument: option(right),
+            ..activity("ib", day, operation)
+        }
+    }
+
+    fn trade(quantity: Decimal, price: Decimal, commission: Decimal) -> (Decimal, Money, Money) {
+        (quantity, money(price), money(commission))
+    }
+
+    fn buy((quantity, price, commission): (Decimal, Money, Money)) -> Operation {
+        Operation::Buy {
+            quantity,
+            price,
+            commission,
+        }
+    }
+
+    fn sell((quantity, price, commission): (Decimal, Money, Money)) -> Operation {
+        Operation::Sell {
+            quantity,
+            price,
+            commission,
+        }
+    }
+
+    fn apply_all(stock: &mut Stock, activities: &[Activity]) -> Vec<Sale> {
+        activities
+            .iter()
+            .filter_map(|activity| stock.apply(activity).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_put_assignment_premium() {
+        let activities = [
+            option_activity(Right::Put, 3, sell(trade(dec!(1), dec!(2), dec!(1)))),
+            option_activity(Right::Put, 21, Operation::Assignment { quantity: dec!(1) }),
+            activity("ib", 21, buy(trade(dec!(100), dec!(50), dec!(0)))),
+            activity("ib", 24, sell(trade(dec!(100), dec!(55), dec!(0)))),
+        ];
+
+        let mut stock = fifo_stock();
+        let sales = apply_all(&mut stock, &activities);
+        assert!(stock.verify_assignments().is_ok());
+        assert_eq!(sales.len(), 1);
+        // Written put premium net of commission lowers cost of the assigned shares
+        assert_eq!(sales[0].cost, Pln::new(4801));
+        assert_eq!(sales[0].revenue, Pln::new(5500));
+    }
+
+    #[test]
+    fn test_call_assignment_into_short() {
+        let activities = [
+            option_activity(Right::Call, 3, sell(trade(dec!(1), dec!(3), dec!(0)))),
+            option_activity(Right::Call, 21, Operation::Assignment { quantity: dec!(1) }),
+            activity("ib", 21, sell(trade(dec!(100), dec!(50), dec!(0)))),
+            activity("ib", 24, buy(trade(dec!(100), dec!(52), dec!(1)))),
+        ];
+
+        let mut stock = Stock {
+            allow_short: true,
+            ..fifo_stock()
+        };
+        let sales = apply_all(&mut stock, &activities);
+        assert!(stock.verify_assignments().is_ok());
+        assert_eq!(sales.len(), 1);
+        assert!(sales[0].short);
+        assert_eq!(sales[0].revenue, Pln::new(5000));
+        // Written call premium lowers cost of closing the short opened by assignment
+        assert_eq!(sales[0].cost, Pln::new(4901));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let activities = [
+            option_activity(Right::Call, 3, buy(trade(dec!(2), dec!(1.5), dec!(2)))),
+            option_activity(Right::Call, 21, Operation::Expiry { quantity: dec!(2) }),
+            option_activity(Right::Put, 4, sell(trade(dec!(1), dec!(2), dec!(1)))),
+            option_activity(Right::Put, 21, Operation::Expiry { quantity: dec!(1) }),
+        ];
+
+        let mut stock = fifo_stock();
+        let sales = apply_all(&mut stock, &activities);
+        assert!(stock.verify_assignments().is_ok());
+        assert_eq!(sales.len(), 2);
+        assert_eq!(sales[0].revenue, Pln::default());
+        assert_eq!(sales[0].cost, Pln::new(302));
+        assert!(sales[0].derivative);
+        // Written option expiring keeps the premium
+        assert!(sales[1].short);
+        assert_eq!(sales[1].revenue, Pln::new(200));
+        assert_eq!(sales[1].cost, Pln::new(1));
+    }
+
+    fn on(date: (i32, u32, u32), activity: Activity) -> Activity {
+        Activity {
+            timestamp: NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            ..activity
+        }
+    }
+
+    #[test]
+    fn test_short_across_years() {
+        let activities = [
+            on(
+                (2022, 12, 20),
+                activity("ib", 1, sell(trade(dec!(10), dec!(100), dec!(2)))),
+            ),
+            on(
+                (2023, 1, 10),
+                activity("ib", 1, buy(trade(dec!(10), dec!(80), dec!(1)))),
+            ),
+        ];
+
+        let mut stock = Stock {
+            allow_short: true,
+            ..fifo_stock()
+        };
+        assert!(stock.apply(&activities[0]).unwrap().is_none());
+        assert_eq!(stock.deferred(2022), Pln::new(1000));
+
+        let sale = stock.apply(&activities[1]).unwrap().unwrap();
+        assert_eq!(stock.deferred(2023), Pln::default());
+        assert!(sale.short);
+        // Proceeds deferred from 2022 are realized in the year of closing
+        assert_eq!(sale.timestamp.year(), 2023);
+        assert_eq!(sale.revenue, Pln::new(1000));
+        assert_eq!(sale.cost, Pln::new(803));
+        assert_eq!(stock.short_positions().count(), 0);
+    }
+
+    #[test]
+    fn test_short_through_rename() {
+        let renamed = |day, operation| Activity {
+            instrument: Instrument::new("CDP"),
+            ..activity("ib", day, operation)
+        };
+        let activities = [
+            activity("ib", 3, sell(trade(dec!(10), dec!(100), dec!(0)))),
+            activity(
+                "ib",
+                4,
+                Operation::Rename {
+                    symbol: "CDP".to_string(),
+                    isin: None,
+                },
+            ),
+            renamed(5, buy(trade(dec!(10), dec!(90), dec!(0)))),
+        ];
+
+        let mut stock = Stock {
+            allow_short: true,
+            ..fifo_stock()
+        };
+        let sales = apply_all(&mut stock, &activities);
+        assert_eq!(sales.len(), 1);
+        assert!(sales[0].short);
+        assert_eq!(sales[0].revenue, Pln::new(1000));
+        assert_eq!(sales[0].cost, Pln::new(900));
+        assert_eq!(stock.positions().count(), 0);
+        assert_eq!(stock.short_positions().count(), 0);
+    }
+
+    fn open_lots(stock: &Stock) -> Vec<(String, Decimal, Pln, Pln)> {
+        let mut lots: Vec<_> = stock
+            .positions()
+            .flat_map(|(_, blocks)| blocks.iter())
+            .map(|block| {
+                (
+                    block.symbol.to_string(),
+                    block.quantity,
+                    block.cost,
+                    block.price(),
+                )
+            })
+            .collect();
+        lots.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+        lots
+    }
+
+    #[test]
+    fn test_split() {
+        let activities = [
+            activity("ib", 3, buy(trade(dec!(10), dec!(100), dec!(1)))),
+            activity("ib", 4, buy(trade(dec!(5), dec!(200), dec!(1)))),
+            activity(
+                "ib",
+                5,
+                Operation::Split {
+                    numerator: dec!(4),
+                    denominator: dec!(1),
+                },
+            ),
+        ];
+
+        let mut stock = fifo_stock();
+        apply_all(&mut stock, &activities);
+        assert_eq!(
+            open_lots(&stock),
+            vec![
+                ("CDR".to_string(), dec!(20), Pln::new(1000), Pln::new(50)),
+                ("CDR".to_string(), dec!(40), Pln::new(1000), Pln::new(25)),
+            ]
+        );
+
+        let reverse = activity(
+            "ib",
+            6,
+            Operation::Split {
+                numerator: dec!(1),
+                denominator: dec!(8),
+            },
+        );
+        let sell = activity("ib", 7, sell(trade(dec!(5), dec!(300), dec!(0))));
+        let sales = apply_all(&mut stock, &[reverse, sell]);
+        // Five shares after reverse split are the whole first lot
+        assert_eq!(sales[0].quantity, dec!(5));
+        assert_eq!(sales[0].cost, Pln::new(1001));
+        assert_eq!(
+            open_lots(&stock),
+            vec![("CDR".to_string(), dec!(2.5), Pln::new(1000), Pln::new(400))]
+        );
+    }
+
+    #[test]
+    fn test_merger() {
+        let activities = [
+            activity("ib", 3, buy(trade(dec!(10), dec!(100), dec!(2)))),
+            activity("ib", 4, buy(trade(dec!(20), dec!(50), dec!(0)))),
+            activity(
+                "ib",
+                5,
+                Operation::Merger {
+                    symbol: "PLW".to_string(),
+                    isin: None,
+                    numerator: dec!(3),
+                    denominator: dec!(2),
+                },
+            ),
+        ];
+
+        let mut stock = fifo_stock();
+        apply_all(&mut stock, &activities);
+        assert_eq!(
+            open_lots(&stock),
+            vec![
+                (
+                    "PLW".to_string(),
+                    dec!(15),
+                    Pln::new(1000),
+                    Pln::new(dec!(66.67))
+                ),
+                (
+                    "PLW".to_string(),
+                    dec!(30),
+                    Pln::new(1000),
+                    Pln::new(dec!(33.33))
+                ),
+            ]
+        );
+
+        let sell = Activity {
+            instrument: Instrument::new("PLW"),
+            ..activity("ib", 6, sell(trade(dec!(15), dec!(80), dec!(0))))
+        };
+        let sales = apply_all(&mut stock, &[sell]);
+        assert_eq!(sales[0].cost, Pln::new(1002));
+        assert_eq!(
+            sales[0].lots[0].timestamp.date(),
+            NaiveDate::from_ymd_opt(2022, 1, 3).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_spin_off() {
+        let activities = [
+            activity("ib", 3, buy(trade(dec!(10), dec!(100), dec!(0)))),
+            activity("ib", 4, buy(trade(dec!(10), dec!(200), dec!(0)))),
+            activity(
+                "ib",
+                5,
+                Operation::SpinOff {
+                    symbol: "GOG".to_string(),
+                    isin: None,
+                    numerator: dec!(1),
+                    denominator: dec!(5),
+                    cost_fraction: dec!(0.2),
+                },
+            ),
+        ];
+
+        let mut stock = fifo_stock();
+        apply_all(&mut stock, &activities);
+        let lots = open_lots(&stock);
+        assert_eq!(
+            lots,
+            vec![
+                ("CDR".to_string(), dec!(10), Pln::new(800), Pln::new(80)),
+                ("CDR".to_string(), dec!(10), Pln::new(1600), Pln::new(160)),
+                ("GOG".to_string(), dec!(2), Pln::new(200), Pln::new(100)),
+                ("GOG".to_string(), dec!(2), Pln::new(400), Pln::new(200)),
+            ]
+        );
+        // Cost is allocated between parent and child without changing the total
+        assert_eq!(
+            lots.iter().fold(Pln::default(), |acc, lot| acc + lot.2),
+            Pln::new(3000)
+        );
+
+        // Open short position owes child shares to the lender
+        let mut stock = Stock {
+            allow_short: true,
+            ..fifo_stock()
+        };
+        stock
+            .apply(&activity(
+                "ib",
+                3,
+                sell(trade(dec!(10), dec!(100), dec!(0))),
+            ))
+            .unwrap();
+        assert!(stock.apply(&activities[2]).is_err());
+    }
+
+    #[test]
+    fn test_prior_year_refund() {
+        let dividend = |account, date, tax| {
+            on(
+                date,
+                activity(
+                    account,
+                    1,
+                    Operation::Dividend {
+                        value: money(dec!(100)),
+                        withholding_tax: money(tax),
+                        country: Some("US".to_string()),
+                    },
+                ),
+            )
+        };
+        let mut activities = [
+            dividend("ib", (2022, 5, 10), dec!(30)),
+            dividend("mbank", (2022, 6, 10), dec!(10)),
+            on(
+                (2023, 2, 1),
+                activity(
+                    "ib",
+                    1,
+                    Operation::WithholdingAdjustment {
+                        value: money(dec!(-15)),
+                        dividend_date: None,
+                    },
+                ),
+            ),
+        ];
+
+        let mut stock = fifo_stock();
+        link_adjustments(&mut activities, &stock);
+        // Refund belongs to the dividend of the same account, not the latest one
+        assert_eq!(
+            dividend_date(&activities[2]),
+            NaiveDate::from_ymd_opt(2022, 5, 10).unwrap()
+        );
+        assert_eq!(tax_year(&activities[2]), 2022);
+        assert!(is_amendment(&activities[2], 2022));
+
+        let mut ledger = Ledger::new(loss::Strategy::Half, vec![]);
+        let treaties = Treaties::load(&None).unwrap();
+        let tax_return = process_annual_activities(
+            &mut stock,
+            &mut ledger,
+            &treaties,
+            2022,
+            activities
+                .iter()
+                .filter(|activity| tax_year(activity) == 2022),
+        )
+        .unwrap();
+        // Refund lowers tax withheld on the ib dividend to the creditable 15, mbank credits 10
+        assert_eq!(tax_return.dividend_tax, Pln::new(13));
+        assert_eq!(tax_return.dividend_excess_tax, Pln::default());
+    }
+}
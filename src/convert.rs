@@ -1,13 +1,22 @@
 use crate::activity::{Activity, Operation};
+use crate::bossa;
+use crate::degiro;
+use crate::equity_plan;
 use crate::interactive_brokers;
 use crate::mbank;
 use crate::nbp;
+use crate::pekao;
+use crate::pko;
+use crate::revolut;
+use crate::trading212;
+use crate::xtb;
 use clap::{Args, ValueEnum};
 use derive_more::{Display, Error};
 
 use serde_json;
 use std::error;
 use std::fs::OpenOptions;
+use std::io::BufReader;
 use std::path::Path;
 
 #[derive(Display, Error, Debug)]
@@ -19,12 +28,34 @@ pub struct Error {
 pub struct CommandArgs {
     source: ConvertSource,
     path: String,
+    #[arg(long)]
+    corporate_actions: Option<String>,
+    #[arg(long)]
+    transfers: Option<String>,
+    /// Account currency of statements which do not report it, e.g. XTB CSV exports
+    #[arg(long)]
+    currency: Option<String>,
+    /// Account statement of brokers exporting it apart from transactions, e.g. DEGIRO
+    #[arg(long)]
+    account_statement: Option<String>,
+    /// ESPP discount was taxed as employment income, purchase fair market value is the cost
+    #[arg(long)]
+    espp_discount_taxed: bool,
 }
 
 #[derive(Display, Clone, ValueEnum)]
 enum ConvertSource {
     Mbank,
     InteractiveBrokers,
+    InteractiveBrokersFlex,
+    Xtb,
+    Revolut,
+    Trading212,
+    Degiro,
+    EquityPlan,
+    Bossa,
+    Pekao,
+    Pko,
 }
 
 impl Error {
@@ -43,21 +74,102 @@ fn format_date(date: Option<&Activity>) -> Result<String, Box<dyn error::Error>>
         .to_string())
 }
 
+/// Replaces converted corporate actions with manual overrides of the same symbol and date,
+/// overrides which do not match any converted corporate action are added.
+fn apply_overrides(
+    activities: &mut Vec<Activity>,
+    path: &str,
+) -> Result<(), Box<dyn error::Error>> {
+    let file = OpenOptions::new().read(true).open(path)?;
+    let reader = BufReader::new(file);
+    let overrides: Vec<Activity> = serde_json::from_reader(reader)?;
+
+    for entry in &overrides {
+        if !entry.operation.is_corporate_action() {
+            return Err(Error::new(&format!(
+                "Override for {} on {} is not a corporate action",
+                entry.instrument.symbol,
+                entry.timestamp.date()
+            ))
+            .into());
+        }
+    }
+
+    activities.retain(|activity| {
+        !activity.operation.is_corporate_action()
+            || !overrides.iter().any(|entry| {
+                entry.instrument.symbol == activity.instrument.symbol
+                    && entry.timestamp.date() == activity.timestamp.date()
+            })
+    });
+    activities.extend(overrides);
+    Ok(())
+}
+
+/// Adds manually supplied transfers, e.g. lots moved in from a broker without statements.
+fn add_transfers(activities: &mut Vec<Activity>, path: &str) -> Result<(), Box<dyn error::Error>> {
+    let file = OpenOptions::new().read(true).open(path)?;
+    let reader = BufReader::new(file);
+    let transfers: Vec<Activity> = serde_json::from_reader(reader)?;
+
+    for entry in &transfers {
+        if !entry.operation.is_transfer() {
+            return Err(Error::new(&format!(
+                "Entry for {} on {} is not a transfer",
+                entry.instrument.symbol,
+                entry.timestamp.date()
+            ))
+            .into());
+        }
+    }
+
+    activities.extend(transfers);
+    Ok(())
+}
+
 pub fn command(args: &CommandArgs) -> Result<(), Box<dyn error::Error>> {
     let path = Path::new(&args.path);
     let mut activities = match &args.source {
         ConvertSource::Mbank => mbank::convert(&path).unwrap(),
         ConvertSource::InteractiveBrokers => interactive_brokers::convert(&path).unwrap(),
+        ConvertSource::InteractiveBrokersFlex => interactive_brokers::convert_flex(path)?,
+        ConvertSource::Xtb => xtb::convert(path, args.currency.as_deref())?,
+        ConvertSource::Revolut => revolut::convert(path)?,
+        ConvertSource::Trading212 => trading212::convert(path)?,
+        ConvertSource::Degiro => {
+            degiro::convert(path, args.account_statement.as_deref().map(Path::new))?
+        }
+        ConvertSource::EquityPlan => equity_plan::convert(path, args.espp_discount_taxed)?,
+        ConvertSource::Bossa => bossa::convert(path)?,
+        ConvertSource::Pekao => pekao::convert(path)?,
+        ConvertSource::Pko => pko::convert(path)?,
     };
 
+    if let Some(path) = &args.corporate_actions {
+        apply_overrides(&mut activities, path)?;
+    }
+
+    if let Some(path) = &args.transfers {
+        add_transfers(&mut activities, path)?;
+    }
+
     activities.sort_by_key(|activity| activity.timestamp);
 
     for activity in &mut activities {
+        if activity.account.is_empty() {
+            activity.account = args.source.to_string();
+        }
+
         let transaction_date = activity.timestamp.date();
         match &mut activity.operation {
             Operation::Dividend {
                 value,
                 withholding_tax,
+                ..
+            }
+            | Operation::PaymentInLieu {
+                value,
+                withholding_tax,
             } => {
                 (value.pln, value.rate) = nbp::convert(&value.original, &transaction_date)?;
                 (withholding_tax.pln, withholding_tax.rate) =
@@ -77,6 +189,43 @@ pub fn command(args: &CommandArgs) -> Result<(), Box<dyn error::Error>> {
                 (commission.pln, commission.rate) =
                     nbp::convert(&commission.original, &transaction_date)?;
             }
+            Operation::Forex {
+                sold,
+                bought,
+                commission,
+            } => {
+                (sold.pln, sold.rate) = nbp::convert(&sold.original, &transaction_date)?;
+                (bought.pln, bought.rate) = nbp::convert(&bought.original, &transaction_date)?;
+                (commission.pln, commission.rate) =
+                    nbp::convert(&commission.original, &transaction_date)?;
+            }
+            Operation::Interest {
+                value,
+                withholding_tax,
+            } => {
+                (value.pln, value.rate) = nbp::convert(&value.original, &transaction_date)?;
+                (withholding_tax.pln, withholding_tax.rate) =
+                    nbp::convert(&withholding_tax.original, &transaction_date)?;
+            }
+            Operation::InterestPaid { value } | Operation::Fee { value, .. } => {
+                (value.pln, value.rate) = nbp::convert(&value.original, &transaction_date)?;
+            }
+            Operation::WithholdingAdjustment {
+                value,
+                dividend_date,
+            } => {
+                // Corrected tax is converted at the rate of the original withholding
+                let date = dividend_date.unwrap_or(transaction_date);
+                (value.pln, value.rate) = nbp::convert(&value.original, &date)?;
+            }
+            Operation::Split { .. }
+            | Operation::Rename { .. }
+            | Operation::Merger { .. }
+            | Operation::SpinOff { .. }
+            | Operation::TransferOut { .. }
+            | Operation::TransferIn { .. }
+            | Operation::Expiry { .. }
+            | Operation::Assignment { .. } => {}
         }
     }
 
@@ -0,0 +1,430 @@
+use crate::activity::{Activity, FeeKind, Instrument, Money, Operation};
+use crate::currency;
+use crate::currency::Pln;
+use crate::interactive_brokers::{match_dividends, Dividend, DividendTax};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use colored::Colorize;
+use csv::{ReaderBuilder, StringRecord};
+use derive_more::{Display, Error};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error;
+use std::path::Path;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Row of the "Transactions" export, unnamed columns hold currency of the preceding column.
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(rename(deserialize = "Date"), deserialize_with = "from_date")]
+    date: NaiveDate,
+    #[serde(rename(deserialize = "Time"), deserialize_with = "from_time")]
+    time: NaiveTime,
+    #[serde(rename(deserialize = "Product"))]
+    product: String,
+    #[serde(rename(deserialize = "ISIN"))]
+    isin: String,
+    #[serde(rename(deserialize = "Reference exchange"), default)]
+    exchange: String,
+    #[serde(rename(deserialize = "Quantity"), deserialize_with = "from_decimal")]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Price"), deserialize_with = "from_decimal")]
+    price: Decimal,
+    #[serde(
+        rename(deserialize = "Price currency"),
+        deserialize_with = "from_optional_currency"
+    )]
+    price_currency: Option<currency::Code>,
+    #[serde(
+        rename(deserialize = "Transaction and/or third party fees"),
+        alias = "Transaction costs",
+        deserialize_with = "from_decimal",
+        default
+    )]
+    fee: Decimal,
+    #[serde(
+        rename(deserialize = "Transaction and/or third party fees currency"),
+        alias = "Transaction costs currency",
+        deserialize_with = "from_optional_currency",
+        default
+    )]
+    fee_currency: Option<currency::Code>,
+    #[serde(rename(deserialize = "Order ID"), alias = "Order Id", default)]
+    order: String,
+}
+
+/// Row of the "Account" export, the unnamed column after "Change" holds its amount.
+#[derive(Debug, Deserialize)]
+struct AccountEntry {
+    #[serde(rename(deserialize = "Date"), deserialize_with = "from_date")]
+    date: NaiveDate,
+    #[serde(rename(deserialize = "Time"), deserialize_with = "from_time")]
+    time: NaiveTime,
+    #[serde(rename(deserialize = "Product"), default)]
+    product: String,
+    #[serde(rename(deserialize = "ISIN"), default)]
+    isin: String,
+    #[serde(rename(deserialize = "Description"))]
+    description: String,
+    #[serde(
+        rename(deserialize = "Change"),
+        deserialize_with = "from_optional_currency"
+    )]
+    currency: Option<currency::Code>,
+    #[serde(
+        rename(deserialize = "Change amount"),
+        deserialize_with = "from_decimal"
+    )]
+    value: Decimal,
+    #[serde(rename(deserialize = "Order Id"), alias = "Order ID", default)]
+    order: String,
+}
+
+fn from_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let date: &str = Deserialize::deserialize(deserializer)?;
+    NaiveDate::parse_from_str(date, "%d-%m-%Y")
+        .map_err(|_| de::Error::custom(format!("Failed to parse \"{}\"", date)))
+}
+
+fn from_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time: &str = Deserialize::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+        .map_err(|_| de::Error::custom(format!("Failed to parse \"{}\"", time)))
+}
+
+/// Parses amount with either decimal point or decimal comma, empty amount is zero.
+fn from_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: &str = Deserialize::deserialize(deserializer)?;
+    // Either separator may be used for decimals, the other one groups thousands. A lone
+    // comma is a decimal comma only when followed by one or two digits, "1,000" is 1000.
+    let value = value.trim();
+    let value = match (value.rfind('.'), value.rfind(',')) {
+        (Some(dot), Some(comma)) if comma > dot => value.replace('.', "").replace(',', "."),
+        (None, Some(comma)) if value.matches(',').count() == 1 && value.len() - comma <= 3 => {
+            value.replace(',', ".")
+        }
+        _ => value.replace(',', ""),
+    };
+    if value.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+
+    Decimal::from_str_exact(&value)
+        .map_err(|_| de::Error::custom(format!("Failed to parse \"{}\"", value)))
+}
+
+fn from_optional_currency<'de, D>(deserializer: D) -> Result<Option<currency::Code>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let code: &str = Deserialize::deserialize(deserializer)?;
+    match code.trim() {
+        "" => Ok(None),
+        code => serde_json::from_value(serde_json::Value::String(code.to_string()))
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("Unsupported currency \"{}\"", code))),
+    }
+}
+
+/// Names unnamed columns after the preceding column, e.g. "Price" is followed by
+/// "Price currency" in transactions and "Change" by "Change amount" in the account export.
+fn normalize_header(header: &StringRecord, suffix: &str) -> StringRecord {
+    let mut previous = String::new();
+    header
+        .iter()
+        .map(|column| match column.trim() {
+            "" => format!("{} {}", previous, suffix),
+            column => {
+                previous = column.to_string();
+                previous.clone()
+            }
+        })
+        .collect()
+}
+
+fn read<T: de::DeserializeOwned>(
+    path: &Path,
+    suffix: &str,
+) -> Result<Vec<T>, Box<dyn error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(b',')
+        .has_headers(true)
+        .flexible(true)
+        .from_path(path)?;
+    let header = normalize_header(reader.headers()?, suffix);
+
+    let mut rows = vec![];
+    for record in reader.records() {
+        rows.push(record?.deserialize(Some(&header))?);
+    }
+    Ok(rows)
+}
+
+/// Product names serve as symbols, statement has no tickers. Parentheses are dropped so
+/// descriptions of dividends parse the same way as in IB statements.
+fn symbol(product: &str) -> String {
+    product.replace(['(', ')'], "").trim().to_string()
+}
+
+fn money(code: &currency::Code, value: Decimal) -> Money {
+    Money {
+        original: currency::new(code, value.abs().round_dp(2)),
+        pln: Pln::default(),
+        rate: None,
+    }
+}
+
+impl AccountEntry {
+    fn kind(&self) -> String {
+        self.description.to_lowercase()
+    }
+
+    /// Taxes charged on an order are booked only in the account, e.g. stamp duty or
+    /// financial transaction tax, while broker fees are also listed with the transaction.
+    fn is_order_tax(&self) -> bool {
+        let kind = self.kind();
+        !self.order.is_empty() && (kind.contains("stamp duty") || kind.contains("transaction tax"))
+    }
+
+    fn description(&self) -> String {
+        format!(
+            "{}({}) {}",
+            symbol(&self.product),
+            self.isin,
+            self.description
+        )
+    }
+}
+
+impl Transaction {
+    fn into_activity(self, entries: &[AccountEntry]) -> Result<Activity, Error> {
+        let code = self.price_currency.ok_or(Error::new(&format!(
+            "Missing price currency of {} on {}",
+            self.product, self.date
+        )))?;
+        let fee_currency = self.fee_currency.unwrap_or(code);
+
+        let mut commission = self.fee.abs();
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.order == self.order && entry.is_order_tax())
+        {
+            match entry.currency == Some(fee_currency) {
+                true => commission += entry.value.abs(),
+                false => println!(
+                    "{prefix} Ignoring {description} of order {order} in currency other than its fees",
+                    prefix = "WARNING".yellow(),
+                    description = entry.description,
+                    order = self.order,
+                ),
+            }
+        }
+
+        let quantity = self.quantity.abs();
+        let price = money(&code, self.price);
+        let commission = money(&fee_currency, commission);
+        let operation = match self.quantity.is_sign_positive() {
+            true => Operation::Buy {
+                quantity,
+                price,
+                commission,
+            },
+            false => Operation::Sell {
+                quantity,
+                price,
+                commission,
+            },
+        };
+
+        Ok(Activity {
+            instrument: Instrument {
+                symbol: symbol(&self.product),
+                isin: Some(self.isin.clone()),
+                exchange: match self.exchange.is_empty() {
+                    true => None,
+                    false => Some(self.exchange.clone()),
+                },
+                ..Default::default()
+            },
+            account: String::new(),
+            timestamp: NaiveDateTime::new(self.date, self.time),
+            operation,
+        })
+    }
+}
+
+/// Converts dividends, their taxes and exchange connection fees booked in the account.
+fn convert_account(entries: &[AccountEntry]) -> Result<Vec<Activity>, Error> {
+    let mut dividends = vec![];
+    let mut taxes = vec![];
+    let mut activities = vec![];
+    for entry in entries {
+        let kind = entry.kind();
+        let is_dividend = kind == "dividend";
+        let is_tax = kind == "dividend tax";
+        let is_fee = kind.contains("connection fee");
+        if !is_dividend && !is_tax && !is_fee {
+            continue;
+        }
+
+        let code = entry.currency.ok_or(Error::new(&format!(
+            "Missing currency of {} on {}",
+            entry.description, entry.date
+        )))?;
+        if is_dividend {
+            dividends.push(Dividend {
+                account: String::new(),
+                description: entry.description(),
+                value: entry.value,
+                currency: code,
+                timestamp: entry.date,
+            });
+        } else if is_tax {
+            taxes.push(DividendTax {
+                account: String::new(),
+                description: entry.description(),
+                value: entry.value,
+                currency: code,
+                timestamp: entry.date,
+            });
+        } else {
+            activities.push(Activity {
+                instrument: Instrument::new(&code.to_string()),
+                account: String::new(),
+                timestamp: NaiveDateTime::new(entry.date, entry.time),
+                operation: Operation::Fee {
+                    value: money(&code, entry.value),
+                    kind: FeeKind::Custody,
+                    description: entry.description.clone(),
+                },
+            });
+        }
+    }
+
+    activities.extend(match_dividends(dividends, taxes));
+    Ok(activities)
+}
+
+/// Joins the transactions export with the account export, which holds dividends and taxes
+/// charged on orders.
+pub fn convert(
+    path: &Path,
+    account: Option<&Path>,
+) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+    let transactions: Vec<Transaction> = read(path, "currency")?;
+    let entries: Vec<AccountEntry> = match account {
+        Some(account) => read(account, "amount")?,
+        None => {
+            println!(
+                "{prefix} No account statement, dividends and order taxes are not converted",
+                prefix = "WARNING".yellow(),
+            );
+            vec![]
+        }
+    };
+
+    let mut activities = transactions
+        .into_iter()
+        .map(|transaction| transaction.into_activity(&entries))
+        .collect::<Result<Vec<_>, _>>()?;
+    activities.extend(convert_account(&entries)?);
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_convert() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let activities = convert(
+            &fixtures.join("degiro_transactions.csv"),
+            Some(&fixtures.join("degiro_account.csv")),
+        )
+        .unwrap();
+
+        assert_eq!(activities.len(), 6);
+        let buy = &activities[0];
+        assert_eq!(buy.instrument.symbol, "APPLE INC. - COMMON ST");
+        assert_eq!(buy.instrument.isin.as_deref(), Some("US0378331005"));
+        assert_eq!(buy.instrument.exchange.as_deref(), Some("NDQ"));
+        assert!(matches!(
+            &buy.operation,
+            Operation::Buy { quantity, price, commission }
+                if *quantity == dec!(10)
+                    && price.original.get_code() == currency::Code::USD
+                    && *price.original.get_value() == dec!(150.25)
+                    && commission.original.get_code() == currency::Code::EUR
+                    && *commission.original.get_value() == dec!(0.50)
+        ));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::Buy { commission, .. } if *commission.original.get_value() == dec!(6.40)
+        ));
+        assert!(matches!(
+            &activities[2].operation,
+            Operation::Sell { quantity, .. } if *quantity == dec!(4)
+        ));
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Fee { value, kind: FeeKind::Custody, .. }
+                if *value.original.get_value() == dec!(2.50)
+        ));
+
+        let dividend = &activities[4];
+        assert_eq!(dividend.instrument.symbol, "APPLE INC. - COMMON ST");
+        assert!(matches!(
+            &dividend.operation,
+            Operation::Dividend { value, withholding_tax, country }
+                if *value.original.get_value() == dec!(2.30)
+                    && *withholding_tax.original.get_value() == dec!(0.35)
+                    && country.as_deref() == Some("US")
+        ));
+        assert!(matches!(
+            &activities[5].operation,
+            Operation::WithholdingAdjustment { value, dividend_date }
+                if *value.original.get_value() == dec!(-0.12)
+                    && *dividend_date == NaiveDate::from_ymd_opt(2022, 5, 12)
+        ));
+    }
+
+    #[test]
+    fn test_decimal_separators() {
+        let parse = |value: &str| {
+            from_decimal(de::value::BorrowedStrDeserializer::<de::value::Error>::new(
+                value,
+            ))
+            .unwrap()
+        };
+        assert_eq!(parse("1,321.10"), dec!(1321.10));
+        assert_eq!(parse("1.321,10"), dec!(1321.10));
+        assert_eq!(parse("-12,5"), dec!(-12.5));
+        assert_eq!(parse("1,000"), dec!(1000));
+        assert_eq!(parse("1,000,000"), dec!(1000000));
+        assert_eq!(parse("0.125"), dec!(0.125));
+        assert_eq!(parse(""), dec!(0));
+    }
+}
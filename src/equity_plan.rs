@@ -0,0 +1,501 @@
+use crate::activity::{Activity, Instrument, Money, Operation};
+use crate::currency;
+use crate::currency::Pln;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use csv::{ReaderBuilder, StringRecord};
+use derive_more::{Display, Error};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error;
+use std::path::Path;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Acquisition or sale of plan shares, common to all platforms.
+#[derive(Debug, PartialEq)]
+enum Event {
+    /// RSU vest, fair market value was taxed as employment income and is the cost basis.
+    Vest {
+        fair_value: Decimal,
+        /// Shares withheld or sold to cover taxes at vest.
+        withheld: Decimal,
+        /// Price of shares sold to cover, fair market value if they were only withheld.
+        sale_price: Option<Decimal>,
+    },
+    /// ESPP purchase at discount to fair market value.
+    Purchase {
+        price: Decimal,
+        fair_value: Option<Decimal>,
+    },
+    Sale {
+        price: Decimal,
+        fees: Decimal,
+    },
+}
+
+#[derive(Debug)]
+struct Entry {
+    date: NaiveDate,
+    symbol: String,
+    quantity: Decimal,
+    currency: currency::Code,
+    event: Event,
+}
+
+/// Row of the generic plan statement for platforms without supported exports, e.g. E*TRADE,
+/// Fidelity NetBenefits or EquatePlus.
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(rename(deserialize = "Date"))]
+    date: NaiveDate,
+    #[serde(rename(deserialize = "Type"))]
+    kind: String,
+    #[serde(rename(deserialize = "Symbol"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Quantity"), deserialize_with = "from_amount")]
+    quantity: Option<Decimal>,
+    #[serde(
+        rename(deserialize = "Price"),
+        deserialize_with = "from_amount",
+        default
+    )]
+    price: Option<Decimal>,
+    #[serde(
+        rename(deserialize = "Fair market value"),
+        deserialize_with = "from_amount",
+        default
+    )]
+    fair_value: Option<Decimal>,
+    #[serde(
+        rename(deserialize = "Withheld quantity"),
+        deserialize_with = "from_amount",
+        default
+    )]
+    withheld: Option<Decimal>,
+    #[serde(
+        rename(deserialize = "Fees"),
+        deserialize_with = "from_amount",
+        default
+    )]
+    fees: Option<Decimal>,
+    #[serde(rename(deserialize = "Currency"))]
+    currency: currency::Code,
+}
+
+/// Parses amount with optional dollar sign and thousands separators, e.g. "$1,234.50".
+fn parse_amount(amount: &str) -> Result<Option<Decimal>, Error> {
+    let value: String = amount
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '.' || *c == '-')
+        .collect();
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    Decimal::from_str_exact(&value)
+        .map(Some)
+        .map_err(|_| Error::new(&format!("Failed to parse \"{}\"", amount)))
+}
+
+fn from_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let amount: &str = Deserialize::deserialize(deserializer)?;
+    parse_amount(amount).map_err(de::Error::custom)
+}
+
+impl TryInto<Entry> for Transaction {
+    type Error = Error;
+
+    fn try_into(self) -> Result<Entry, Self::Error> {
+        let missing = |name: &str| {
+            Error::new(&format!(
+                "Missing {} of {} {} on {}",
+                name, self.kind, self.symbol, self.date
+            ))
+        };
+        let event = match self.kind.to_lowercase().as_str() {
+            "vest" => Event::Vest {
+                fair_value: self
+                    .fair_value
+                    .or(self.price)
+                    .ok_or(missing("fair market value"))?,
+                withheld: self.withheld.unwrap_or_default(),
+                sale_price: self.price.filter(|_| self.fair_value.is_some()),
+            },
+            "purchase" => Event::Purchase {
+                price: self.price.ok_or(missing("price"))?,
+                fair_value: self.fair_value,
+            },
+            "sale" | "sell" => Event::Sale {
+                price: self.price.ok_or(missing("price"))?,
+                fees: self.fees.unwrap_or_default(),
+            },
+            kind => return Err(Error::new(&format!("Unknown type \"{}\"", kind))),
+        };
+
+        Ok(Entry {
+            date: self.date,
+            // Sales may be reported with negative quantity
+            quantity: self.quantity.ok_or(missing("quantity"))?.abs(),
+            symbol: self.symbol,
+            currency: self.currency,
+            event,
+        })
+    }
+}
+
+/// Returns value of the named column, Schwab lists details of a transaction in rows below
+/// it, each kind of transaction with its own header.
+fn field<'a>(header: &StringRecord, record: &'a StringRecord, column: &str) -> Option<&'a str> {
+    let index = header.iter().position(|name| name == column)?;
+    record.get(index).map(str::trim)
+}
+
+fn schwab_amount(
+    header: &StringRecord,
+    record: &StringRecord,
+    column: &str,
+) -> Result<Option<Decimal>, Error> {
+    field(header, record, column).map_or(Ok(None), parse_amount)
+}
+
+/// Converts transaction with its details from Schwab Equity Award Center history.
+fn schwab_entry(
+    header: &StringRecord,
+    record: &StringRecord,
+    details: &[(StringRecord, StringRecord)],
+) -> Result<Option<Entry>, Error> {
+    // Dates are reported as "12/15/2023" or "12/15/2023 as of 12/14/2023"
+    let date = field(header, record, "Date").unwrap_or_default();
+    let date = date.split_whitespace().next().unwrap_or_default();
+    let date = NaiveDate::parse_from_str(date, "%m/%d/%Y")
+        .map_err(|_| Error::new(&format!("Failed to parse \"{}\"", date)))?;
+    let symbol = field(header, record, "Symbol")
+        .unwrap_or_default()
+        .to_string();
+    let action = field(header, record, "Action").unwrap_or_default();
+    // Dividends, wires and journals are reported by the brokerage account
+    if !["Lapse", "Release", "Deposit", "Sale"].contains(&action) {
+        return Ok(None);
+    }
+    let quantity = schwab_amount(header, record, "Quantity")?.ok_or(Error::new(&format!(
+        "Missing quantity of {} on {}",
+        symbol, date
+    )))?;
+    let detail = |column: &str| -> Result<Option<Decimal>, Error> {
+        for (header, record) in details {
+            if let Some(value) = schwab_amount(header, record, column)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    };
+    let missing = |name: &str| Error::new(&format!("Missing {} of {} on {}", name, symbol, date));
+
+    let event = match action {
+        "Lapse" | "Release" => Event::Vest {
+            fair_value: detail("FairMarketValuePrice")?.ok_or(missing("fair market value"))?,
+            withheld: detail("SharesSoldWithheldForTaxes")?.unwrap_or_default(),
+            sale_price: detail("SalePrice")?,
+        },
+        "Deposit" if field(header, record, "Description") == Some("ESPP") => Event::Purchase {
+            price: detail("PurchasePrice")?.ok_or(missing("purchase price"))?,
+            fair_value: detail("PurchaseFairMarketValue")?,
+        },
+        "Sale" => {
+            let fees = schwab_amount(header, record, "FeesAndCommissions")?.unwrap_or_default();
+            let price = match detail("SalePrice")? {
+                Some(price) => price,
+                None => {
+                    let amount =
+                        schwab_amount(header, record, "Amount")?.ok_or(missing("amount"))?;
+                    ((amount + fees.abs()) / quantity).round_dp(4)
+                }
+            };
+            Event::Sale { price, fees }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Entry {
+        date,
+        symbol,
+        quantity,
+        currency: currency::Code::USD,
+        event,
+    }))
+}
+
+fn read_schwab(path: &Path) -> Result<Vec<Entry>, Box<dyn error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+
+    let mut header = None;
+    let mut transactions: Vec<(StringRecord, Vec<(StringRecord, StringRecord)>)> = vec![];
+    let mut detail_header: Option<StringRecord> = None;
+    for record in reader.records() {
+        let record = record?;
+        let first = record.get(0).unwrap_or_default().trim();
+        if header.is_none() {
+            if first == "Date" {
+                header = Some(record);
+            }
+            continue;
+        }
+
+        if !first.is_empty() {
+            transactions.push((record, vec![]));
+            detail_header = None;
+            continue;
+        }
+        match (&detail_header, transactions.last_mut()) {
+            (Some(detail_header), Some((_, details))) => {
+                details.push((detail_header.clone(), record))
+            }
+            _ => detail_header = Some(record),
+        }
+    }
+
+    let header = header.ok_or(Error::new("Missing header of Schwab transaction history"))?;
+    let mut entries = vec![];
+    for (record, details) in &transactions {
+        entries.extend(schwab_entry(&header, record, details)?);
+    }
+    Ok(entries)
+}
+
+fn read_generic(path: &Path) -> Result<Vec<Entry>, Box<dyn error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_path(path)?;
+
+    let transactions = reader
+        .deserialize::<Transaction>()
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(transactions
+        .into_iter()
+        .map(|transaction| transaction.try_into())
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+impl Entry {
+    fn money(&self, value: Decimal) -> Money {
+        Money {
+            original: currency::new(&self.currency, value.abs()),
+            pln: Pln::default(),
+            rate: None,
+        }
+    }
+
+    fn activity(&self, timestamp: NaiveDateTime, operation: Operation) -> Activity {
+        Activity {
+            instrument: Instrument::new(&self.symbol),
+            account: String::new(),
+            timestamp,
+            operation,
+        }
+    }
+
+    /// Shares sold or withheld to cover taxes are disposed right after the vest, at fair
+    /// market value unless sold at a different price.
+    fn into_activities(self, discount_taxed: bool) -> Result<Vec<Activity>, Error> {
+        let timestamp = NaiveDateTime::new(self.date, NaiveTime::MIN);
+        let buy = |price: Decimal| Operation::Buy {
+            quantity: self.quantity,
+            price: self.money(price),
+            commission: self.money(Decimal::ZERO),
+        };
+
+        match &self.event {
+            Event::Vest {
+                fair_value,
+                withheld,
+                sale_price,
+            } => {
+                let mut activities = vec![self.activity(timestamp, buy(*fair_value))];
+                if !withheld.is_zero() {
+                    activities.push(self.activity(
+                        timestamp + Duration::seconds(1),
+                        Operation::Sell {
+                            quantity: *withheld,
+                            price: self.money(sale_price.unwrap_or(*fair_value)),
+                            commission: self.money(Decimal::ZERO),
+                        },
+                    ));
+                }
+                Ok(activities)
+            }
+            Event::Purchase { price, fair_value } => {
+                let price = match (discount_taxed, fair_value) {
+                    (true, Some(fair_value)) => *fair_value,
+                    (true, None) => {
+                        return Err(Error::new(&format!(
+                            "Missing fair market value of {} purchased on {}, required when the discount is taxed",
+                            self.symbol, self.date
+                        )))
+                    }
+                    (false, _) => *price,
+                };
+                Ok(vec![self.activity(timestamp, buy(price))])
+            }
+            Event::Sale { price, fees } => Ok(vec![self.activity(
+                timestamp,
+                Operation::Sell {
+                    quantity: self.quantity,
+                    price: self.money(*price),
+                    commission: self.money(*fees),
+                },
+            )]),
+        }
+    }
+}
+
+/// Converts Schwab Equity Award Center history or the generic plan statement. ESPP shares
+/// cost their purchase price, or fair market value when the discount was taxed as
+/// employment income.
+pub fn convert(path: &Path, discount_taxed: bool) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    let is_schwab = match reader.records().next() {
+        Some(record) => {
+            let record = record?;
+            let first = record.get(0).unwrap_or_default();
+            first.starts_with("Transaction Details") || record.iter().any(|name| name == "Action")
+        }
+        None => false,
+    };
+
+    let entries = match is_schwab {
+        true => read_schwab(path)?,
+        false => read_generic(path)?,
+    };
+    let activities = entries
+        .into_iter()
+        .map(|entry| entry.into_activities(discount_taxed))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(activities.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    fn convert_statement(name: &str, statement: &str, discount_taxed: bool) -> Vec<Activity> {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path, discount_taxed).unwrap();
+        fs::remove_file(&path).unwrap();
+        activities
+    }
+
+    fn price(operation: &Operation) -> Decimal {
+        match operation {
+            Operation::Buy { price, .. } | Operation::Sell { price, .. } => {
+                *price.original.get_value()
+            }
+            _ => panic!("Expected trade"),
+        }
+    }
+
+    #[test]
+    fn test_schwab() {
+        let statement = "\
+\"Transaction Details for Equity Award Center account XXX-123 as of 01/05/2024\"
+\"Date\",\"Action\",\"Symbol\",\"Description\",\"Quantity\",\"FeesAndCommissions\",\"DisbursementElection\",\"Amount\"
+\"06/30/2023\",\"Deposit\",\"ACME\",\"ESPP\",\"20\",\"\",\"\",\"\"
+\"\",\"PurchaseDate\",\"PurchasePrice\",\"SubscriptionDate\",\"SubscriptionFairMarketValue\",\"PurchaseFairMarketValue\"
+\"\",\"06/30/2023\",\"$88.00\",\"01/03/2023\",\"$104.00\",\"$120.00\"
+\"12/15/2023\",\"Lapse\",\"ACME\",\"Restricted Stock Lapse\",\"10\",\"\",\"\",\"\"
+\"\",\"AwardDate\",\"AwardId\",\"FairMarketValuePrice\",\"SalePrice\",\"SharesSoldWithheldForTaxes\",\"NetSharesDeposited\",\"Taxes\"
+\"\",\"03/01/2021\",\"123456\",\"$133.32\",\"$133.10\",\"4\",\"6\",\"$532.40\"
+\"12/20/2023 as of 12/19/2023\",\"Sale\",\"ACME\",\"Share Sale\",\"6\",\"$0.05\",\"\",\"$850.15\"
+\"\",\"Type\",\"Shares\",\"SalePrice\",\"GrantId\",\"VestDate\",\"VestFairMarketValue\",\"GrossProceeds\"
+\"\",\"RS\",\"6\",\"$141.70\",\"123456\",\"12/15/2023\",\"$133.32\",\"$850.20\"
+\"12/28/2023\",\"Wire Transfer\",\"\",\"Cash Disbursement\",\"\",\"\",\"\",\"-$850.15\"
+";
+        let activities = convert_statement("stock_tax_test_schwab.csv", statement, false);
+
+        assert_eq!(activities.len(), 4);
+        assert!(matches!(
+            &activities[0].operation,
+            Operation::Buy { quantity, .. } if *quantity == dec!(20)
+        ));
+        assert_eq!(price(&activities[0].operation), dec!(88));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::Buy { quantity, .. } if *quantity == dec!(10)
+        ));
+        assert_eq!(price(&activities[1].operation), dec!(133.32));
+        assert!(matches!(
+            &activities[2].operation,
+            Operation::Sell { quantity, .. } if *quantity == dec!(4)
+        ));
+        assert_eq!(price(&activities[2].operation), dec!(133.10));
+        assert!(activities[2].timestamp > activities[1].timestamp);
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Sell { quantity, commission, .. }
+                if *quantity == dec!(6) && *commission.original.get_value() == dec!(0.05)
+        ));
+        assert_eq!(price(&activities[3].operation), dec!(141.70));
+    }
+
+    #[test]
+    fn test_generic() {
+        let statement = "\
+Date,Type,Symbol,Quantity,Price,Fair market value,Withheld quantity,Fees,Currency
+2023-03-15,Vest,ACME,8,,50.00,3,,USD
+2023-06-30,Purchase,ACME,12,42.50,50.00,,,USD
+2023-09-01,Sale,ACME,-5,55.00,,,4.95,USD
+";
+        let activities = convert_statement("stock_tax_test_equity_plan.csv", statement, true);
+
+        assert_eq!(activities.len(), 4);
+        assert_eq!(price(&activities[0].operation), dec!(50));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::Sell { quantity, .. } if *quantity == dec!(3)
+        ));
+        assert_eq!(price(&activities[1].operation), dec!(50));
+        assert_eq!(price(&activities[2].operation), dec!(50));
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Sell { quantity, commission, .. }
+                if *quantity == dec!(5) && *commission.original.get_value() == dec!(4.95)
+        ));
+
+        let statement = "\
+Date,Type,Symbol,Quantity,Price,Fair market value,Withheld quantity,Fees,Currency
+2023-06-30,Purchase,ACME,12,42.50,,,,USD
+";
+        let path = env::temp_dir().join("stock_tax_test_equity_plan_fair_value.csv");
+        fs::write(&path, statement).unwrap();
+        assert!(convert(&path, true).is_err());
+        assert_eq!(
+            price(&convert(&path, false).unwrap()[0].operation),
+            dec!(42.5)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
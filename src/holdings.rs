@@ -0,0 +1,203 @@
+use crate::activity;
+use crate::compute::{self, Block, MatchingArgs};
+use crate::currency::{Builder, Currency, Pln};
+use crate::nbp;
+use crate::tax::Tax;
+use chrono::NaiveDate;
+use clap::Args;
+use colored::Colorize;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+
+#[derive(Args)]
+pub struct CommandArgs {
+    path: String,
+    #[command(flatten)]
+    matching: MatchingArgs,
+    #[arg(long)]
+    prices: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Price {
+    symbol: String,
+    #[serde(deserialize_with = "activity::from_currency")]
+    price: Box<dyn Currency>,
+    #[serde(default, deserialize_with = "activity::from_optional_currency")]
+    commission: Option<Box<dyn Currency>>,
+    date: NaiveDate,
+}
+
+struct Valuation {
+    price: Box<dyn Currency>,
+    pln: Pln,
+    commission: Pln,
+    rate: Option<nbp::Rate>,
+}
+
+fn load_prices(path: &Option<String>) -> Result<HashMap<String, Valuation>, Box<dyn error::Error>> {
+    let prices: Vec<Price> = match path {
+        Some(path) => {
+            let file = OpenOptions::new().read(true).open(path)?;
+            let reader = BufReader::new(file);
+            serde_json::from_reader(reader)?
+        }
+        None => vec![],
+    };
+
+    let mut valuations = HashMap::new();
+    for price in prices {
+        let (pln, rate) = nbp::convert(&price.price, &price.date)?;
+        let commission = match &price.commission {
+            Some(commission) => nbp::convert(commission, &price.date)?.0,
+            None => Pln::default(),
+        };
+        valuations.insert(
+            price.symbol,
+            Valuation {
+                price: price.price,
+                pln,
+                commission,
+                rate,
+            },
+        );
+    }
+    Ok(valuations)
+}
+
+fn print_position(
+    symbol: &str,
+    account: &str,
+    blocks: &[&Block],
+    multiplier: Decimal,
+    valuation: Option<&Valuation>,
+) {
+    let quantity = blocks
+        .iter()
+        .fold(Decimal::ZERO, |acc, block| acc + block.quantity);
+    let cost = blocks.iter().fold(Pln::default(), |acc, block| {
+        acc + block.cost + block.commission
+    });
+
+    println!(
+        "{prefix} {symbol} account: {account} quantity: {quantity} cost: {cost} average: {average}",
+        prefix = "Position".green(),
+        account = if account.is_empty() { "-" } else { account },
+        average = cost / quantity,
+    );
+
+    for block in blocks {
+        println!(
+            "  {date}: Open block quantity: {quantity} cost: {cost} price: {price} commission: {commission}",
+            date = block.timestamp.date(),
+            quantity = block.quantity,
+            cost = block.cost,
+            price = block.price(),
+            commission = block.commission,
+        );
+    }
+
+    if let Some(valuation) = valuation {
+        let value = valuation.pln * (quantity * multiplier);
+        let gain = value - valuation.commission - cost;
+        let tax = if gain > Pln::new(0) {
+            gain * Tax::new(19)
+        } else {
+            Pln::default()
+        };
+
+        println!(
+            "  value: {value} price: {price_pln} ({price_org}) commission: {commission} rate: {rate} unrealized gain: {gain} tax on sale: {tax}",
+            price_pln = valuation.pln,
+            commission = valuation.commission,
+            price_org = valuation.price,
+            rate = valuation
+                .rate
+                .as_ref()
+                .map_or("-".to_string(), |rate| rate.to_string()),
+        );
+    }
+}
+
+fn print_short_position(symbol: &str, account: &str, blocks: &[&Block]) {
+    let quantity = blocks
+        .iter()
+        .fold(Decimal::ZERO, |acc, block| acc + block.quantity);
+    let proceeds = blocks
+        .iter()
+        .fold(Pln::default(), |acc, block| acc + block.cost);
+
+    println!(
+        "{prefix} {symbol} account: {account} quantity: {quantity} proceeds: {proceeds} average: {average}",
+        prefix = "Short position".red(),
+        account = if account.is_empty() { "-" } else { account },
+        average = proceeds / quantity,
+    );
+
+    for block in blocks {
+        println!(
+            "  {date}: Short block quantity: {quantity} proceeds: {proceeds} price: {price} commission: {commission}",
+            date = block.timestamp.date(),
+            quantity = block.quantity,
+            proceeds = block.cost,
+            price = block.price(),
+            commission = block.commission,
+        );
+    }
+}
+
+pub fn command(args: &CommandArgs) -> Result<(), Box<dyn error::Error>> {
+    let activities = compute::load_activities(&args.path)?;
+    let (stock, _) = compute::replay(&args.matching, &activities)?;
+    let valuations = load_prices(&args.prices)?;
+    let multipliers: HashMap<_, _> = activities
+        .iter()
+        .map(|activity| {
+            (
+                activity.instrument.symbol.as_str(),
+                activity.instrument.multiplier(),
+            )
+        })
+        .collect();
+
+    let mut positions = BTreeMap::<(&str, &str), Vec<&Block>>::new();
+    for (_, blocks) in stock.positions() {
+        for block in blocks {
+            positions
+                .entry((&block.symbol, &block.account))
+                .or_default()
+                .push(block);
+        }
+    }
+
+    println!("Lot matching: {}", stock.method());
+    for ((symbol, account), blocks) in positions {
+        print_position(
+            symbol,
+            account,
+            &blocks,
+            multipliers.get(symbol).copied().unwrap_or(Decimal::ONE),
+            valuations.get(symbol),
+        );
+    }
+
+    let mut shorts = BTreeMap::<(&str, &str), Vec<&Block>>::new();
+    for (_, blocks) in stock.short_positions() {
+        for block in blocks {
+            shorts
+                .entry((&block.symbol, &block.account))
+                .or_default()
+                .push(block);
+        }
+    }
+
+    for ((symbol, account), blocks) in shorts {
+        print_short_position(symbol, account, &blocks);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use crate::activity::{Activity, Instrument};
+use colored::Colorize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Resolves instruments to keys identifying the same security across brokers and activities.
+#[derive(Debug, Default)]
+pub struct Identities {
+    isins: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Identities {
+    pub fn new(activities: &[Activity]) -> Identities {
+        let mut isins = BTreeMap::<String, BTreeSet<String>>::new();
+        let mut symbols = BTreeMap::<String, BTreeSet<String>>::new();
+
+        for activity in activities {
+            let instrument = &activity.instrument;
+            if let Some(isin) = &instrument.isin {
+                isins
+                    .entry(instrument.symbol.to_string())
+                    .or_default()
+                    .insert(isin.to_string());
+                symbols
+                    .entry(isin.to_string())
+                    .or_default()
+                    .insert(instrument.symbol.to_string());
+            }
+        }
+
+        for (symbol, values) in isins.iter().filter(|(_, values)| values.len() > 1) {
+            println!(
+                "{prefix} Symbol {symbol} maps to several ISINs: {values:?}",
+                prefix = "WARNING".yellow(),
+            );
+        }
+
+        for (isin, values) in symbols.iter().filter(|(_, values)| values.len() > 1) {
+            println!(
+                "{prefix} ISIN {isin} is known under several symbols: {values:?}",
+                prefix = "WARNING".yellow(),
+            );
+        }
+
+        Identities { isins }
+    }
+
+    /// Returns ISIN of the instrument, or ISIN unambiguously associated with its symbol,
+    /// falling back to the symbol itself.
+    pub fn key(&self, instrument: &Instrument) -> String {
+        self.resolve(&instrument.symbol, &instrument.isin)
+    }
+
+    pub fn resolve(&self, symbol: &str, isin: &Option<String>) -> String {
+        if let Some(isin) = isin {
+            return isin.to_string();
+        }
+
+        match self.isins.get(symbol) {
+            Some(values) if values.len() == 1 => values.iter().next().unwrap().to_string(),
+            _ => symbol.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::Operation;
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    fn split(symbol: &str, isin: Option<&str>) -> Activity {
+        Activity {
+            instrument: Instrument {
+                symbol: symbol.to_string(),
+                isin: isin.map(|isin| isin.to_string()),
+                ..Default::default()
+            },
+            account: String::new(),
+            timestamp: NaiveDate::from_ymd_opt(2022, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            operation: Operation::Split {
+                numerator: dec!(2),
+                denominator: dec!(1),
+            },
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let identities = Identities::new(&[
+            split("CDR", Some("PLOPTTC00011")),
+            split("SHELL", Some("GB00BP6MXD84")),
+            split("SHELL", Some("NL0000009470")),
+        ]);
+
+        assert_eq!(identities.key(&Instrument::new("CDR")), "PLOPTTC00011");
+        assert_eq!(identities.key(&Instrument::new("SHELL")), "SHELL");
+        assert_eq!(identities.key(&Instrument::new("AAPL")), "AAPL");
+        assert_eq!(
+            identities.resolve("CDR.WA", &Some("PLOPTTC00011".to_string())),
+            "PLOPTTC00011"
+        );
+    }
+}
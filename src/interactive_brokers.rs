@@ -1,14 +1,15 @@
-use crate::activity::{Activity, Money, Operation};
+use crate::activity::{
+    Activity, Derivative, DerivativeKind, FeeKind, Instrument, Money, Operation, Right,
+};
 use crate::currency;
 use crate::currency::Pln;
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
-use csv::ReaderBuilder;
+use colored::Colorize;
+use csv::{ReaderBuilder, StringRecord};
 use derive_more::{self, Display};
 use rust_decimal::Decimal;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::error;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
 use std::path::Path;
 
 #[derive(derive_more::Error, Display, Debug)]
@@ -18,6 +19,10 @@ pub struct Error {
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Transaction {
+    #[serde(rename(deserialize = "Account"), default)]
+    account: String,
+    #[serde(rename(deserialize = "Asset Category"))]
+    category: String,
     #[serde(rename(deserialize = "Symbol"))]
     symbol: String,
     #[serde(rename(deserialize = "Quantity"))]
@@ -34,24 +39,67 @@ struct Transaction {
     timestamp: NaiveDateTime,
     #[serde(rename(deserialize = "Comm/Fee"))]
     commission: Decimal,
+    #[serde(rename(deserialize = "Code"), default)]
+    code: String,
+    /// Contract terms reported separately, e.g. by Flex Query, otherwise parsed from symbol.
+    #[serde(skip)]
+    derivative: Option<Derivative>,
 }
 
 #[derive(Debug, Deserialize)]
-struct Dividend {
-    #[serde(rename(deserialize = "Description"), deserialize_with = "from_symbol")]
+struct ForexTransaction {
+    #[serde(rename(deserialize = "Account"), default)]
+    account: String,
+    #[serde(rename(deserialize = "Symbol"))]
     symbol: String,
+    #[serde(rename(deserialize = "Quantity"))]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Proceeds"))]
+    proceeds: Decimal,
+    #[serde(rename(deserialize = "Date/Time"), deserialize_with = "from_timestamp")]
+    timestamp: NaiveDateTime,
+    /// Reported in the base currency of the account, column is named after it.
+    #[serde(skip)]
+    commission: Decimal,
+    #[serde(skip)]
+    commission_currency: Option<currency::Code>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Dividend {
+    #[serde(rename(deserialize = "Account"), default)]
+    pub account: String,
+    #[serde(rename(deserialize = "Description"))]
+    pub description: String,
     #[serde(rename(deserialize = "Amount"))]
-    value: Decimal,
+    pub value: Decimal,
     #[serde(rename(deserialize = "Currency"))]
-    currency: currency::Code,
+    pub currency: currency::Code,
     #[serde(rename(deserialize = "Date"), deserialize_with = "from_date")]
-    timestamp: NaiveDate,
+    pub timestamp: NaiveDate,
 }
 
 #[derive(Debug, Deserialize)]
-struct DividendTax {
-    #[serde(rename(deserialize = "Description"), deserialize_with = "from_symbol")]
-    symbol: String,
+pub struct DividendTax {
+    #[serde(rename(deserialize = "Account"), default)]
+    pub account: String,
+    #[serde(rename(deserialize = "Description"))]
+    pub description: String,
+    #[serde(rename(deserialize = "Amount"))]
+    pub value: Decimal,
+    #[serde(rename(deserialize = "Currency"))]
+    pub currency: currency::Code,
+    #[serde(rename(deserialize = "Date"), deserialize_with = "from_date")]
+    pub timestamp: NaiveDate,
+}
+
+/// Cash entry of "Interest", "Fees" and "Other Fees" sections, also interest withholding tax.
+#[derive(Debug, Deserialize)]
+struct CashEntry {
+    #[serde(rename(deserialize = "Account"), default)]
+    account: String,
+    #[serde(rename(deserialize = "Description"))]
+    description: String,
     #[serde(rename(deserialize = "Amount"))]
     value: Decimal,
     #[serde(rename(deserialize = "Currency"))]
@@ -60,17 +108,102 @@ struct DividendTax {
     timestamp: NaiveDate,
 }
 
-fn from_symbol<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let symbol: &str = Deserialize::deserialize(deserializer)?;
-    match symbol.split("(").next() {
-        Some(symbol) => Ok(symbol.to_string()),
-        _ => Err(de::Error::custom("")),
+#[derive(Debug, Deserialize)]
+struct FinancialInstrument {
+    #[serde(rename(deserialize = "Symbol"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Conid"))]
+    contract_id: String,
+    #[serde(rename(deserialize = "Security ID"))]
+    isin: String,
+    #[serde(rename(deserialize = "Listing Exch"))]
+    exchange: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DerivativeInstrument {
+    #[serde(rename(deserialize = "Symbol"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Conid"))]
+    contract_id: String,
+    #[serde(rename(deserialize = "Underlying"))]
+    underlying: String,
+    #[serde(rename(deserialize = "Multiplier"))]
+    multiplier: Decimal,
+    #[serde(rename(deserialize = "Expiry"), deserialize_with = "from_date")]
+    expiry: NaiveDate,
+}
+
+#[derive(Debug, Deserialize)]
+struct Transfer {
+    #[serde(rename(deserialize = "Account"), default)]
+    account: String,
+    #[serde(rename(deserialize = "Symbol"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Date"), deserialize_with = "from_date")]
+    timestamp: NaiveDate,
+    #[serde(rename(deserialize = "Direction"))]
+    direction: String,
+    #[serde(rename(deserialize = "Qty"))]
+    quantity: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct CorporateAction {
+    #[serde(rename(deserialize = "Account"), default)]
+    account: String,
+    #[serde(rename(deserialize = "Description"))]
+    description: String,
+    #[serde(rename(deserialize = "Date/Time"), deserialize_with = "from_timestamp")]
+    timestamp: NaiveDateTime,
+}
+
+fn parse_symbol(description: &str) -> String {
+    description
+        .split("(")
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn parse_country(description: &str) -> Option<String> {
+    let isin = description.split("(").nth(1)?.split(")").next()?;
+    match isin.len() == 12 && isin.chars().all(|c| c.is_ascii_alphanumeric()) {
+        true => Some(isin[..2].to_string()),
+        false => None,
+    }
+}
+
+/// Parses ratio following corporate action keyword, e.g. "Split 4 for 1" or
+/// "Merged(Acquisition) WITH US0079031078 1.7234 for 1".
+fn parse_ratio(description: &str, action: &str) -> Option<(Decimal, Decimal)> {
+    let (_, ratio) = description.split_once(action)?;
+    let words: Vec<_> = ratio.split_whitespace().collect();
+    words.windows(3).find_map(|words| {
+        if !words[1].eq_ignore_ascii_case("for") {
+            return None;
+        }
+        let numerator = Decimal::from_str_exact(words[0]).ok()?;
+        let denominator = Decimal::from_str_exact(words[2]).ok()?;
+        Some((numerator, denominator))
+    })
+}
+
+/// Parses symbol of security resulting from corporate action, e.g. "(META, META PLATFORMS INC, US30303M1027)".
+fn parse_target(description: &str) -> Option<String> {
+    let (_, target) = description.rsplit_once("(")?;
+    let symbol = target.split(",").next()?.trim();
+    match symbol.is_empty() {
+        true => None,
+        false => Some(symbol.to_string()),
     }
 }
 
+fn parse_target_isin(description: &str) -> Option<String> {
+    let (_, target) = description.rsplit_once("(")?;
+    Instrument::parse_isin(target)
+}
+
 fn from_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
 where
     D: Deserializer<'de>,
@@ -111,12 +244,63 @@ impl Error {
     }
 }
 
+/// Parses option symbol, e.g. "AAPL 21JAN22 150 C", futures terms come from instrument information.
+fn parse_derivative(category: &str, symbol: &str) -> Option<Derivative> {
+    match category {
+        "Equity and Index Options" => {
+            let parts: Vec<_> = symbol.split_whitespace().collect();
+            let [underlying, expiry, strike, right] = parts[..] else {
+                return None;
+            };
+            Some(Derivative {
+                kind: DerivativeKind::Option,
+                underlying: underlying.to_string(),
+                multiplier: Decimal::ONE_HUNDRED,
+                expiry: NaiveDate::parse_from_str(expiry, "%d%b%y").ok(),
+                strike: strike.parse().ok(),
+                right: match right {
+                    "C" => Some(Right::Call),
+                    "P" => Some(Right::Put),
+                    _ => None,
+                },
+            })
+        }
+        "Futures" => Some(Derivative {
+            kind: DerivativeKind::Future,
+            underlying: symbol.to_string(),
+            multiplier: Decimal::ONE,
+            expiry: None,
+            strike: None,
+            right: None,
+        }),
+        _ => None,
+    }
+}
+
 impl Into<Activity> for Transaction {
     fn into(self) -> Activity {
+        let derivative = self
+            .derivative
+            .or_else(|| parse_derivative(&self.category, &self.symbol));
+        let codes: Vec<_> = self.code.split(';').collect();
+        let expired = derivative.is_some() && codes.contains(&"Ep");
+        let assigned = derivative.is_some() && (codes.contains(&"A") || codes.contains(&"Ex"));
+
         Activity {
-            symbol: self.symbol,
+            instrument: Instrument {
+                symbol: self.symbol,
+                derivative,
+                ..Default::default()
+            },
+            account: self.account.clone(),
             timestamp: self.timestamp,
             operation: match self.quantity.is_sign_positive() {
+                _ if expired => Operation::Expiry {
+                    quantity: self.quantity.abs(),
+                },
+                _ if assigned => Operation::Assignment {
+                    quantity: self.quantity.abs(),
+                },
                 true => Operation::Buy {
                     quantity: self.quantity,
                     price: Money {
@@ -148,112 +332,1382 @@ impl Into<Activity> for Transaction {
     }
 }
 
-impl TryInto<Activity> for (Dividend, DividendTax) {
+impl TryInto<Activity> for ForexTransaction {
     type Error = Error;
 
     fn try_into(self) -> Result<Activity, Self::Error> {
-        let symbol_mismatch = self.0.symbol != self.1.symbol;
-        let timestamp_mismatch = self.0.timestamp != self.1.timestamp;
-
-        if symbol_mismatch || timestamp_mismatch {
-            return Err(Error::new("Mismatch between dividend and tax"));
-        }
+        let parse = |code: &str| -> Result<currency::Code, Error> {
+            serde_json::from_value(serde_json::Value::String(code.to_string())).or(Err(Error::new(
+                &format!("Unsupported currency {} in {}", code, self.symbol),
+            )))
+        };
+        let (base, quote) = self.symbol.split_once('.').ok_or(Error::new(&format!(
+            "Failed to parse currency pair {}",
+            self.symbol
+        )))?;
+        let (base, quote) = (parse(base)?, parse(quote)?);
 
-        let dividend = self.0;
-        let tax = self.1;
-        let timestamp = NaiveDateTime::new(
-            dividend.timestamp,
-            NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
-        );
+        let money = |code: &currency::Code, value: Decimal| Money {
+            original: currency::new(code, value.abs().round_dp(2)),
+            pln: Pln::default(),
+            rate: None,
+        };
+        let (sold, bought) = match self.quantity.is_sign_positive() {
+            true => (money(&quote, self.proceeds), money(&base, self.quantity)),
+            false => (money(&base, self.quantity), money(&quote, self.proceeds)),
+        };
 
         Ok(Activity {
-            symbol: dividend.symbol,
-            timestamp: timestamp,
-            operation: Operation::Dividend {
+            instrument: Instrument::new(&self.symbol),
+            account: self.account.clone(),
+            timestamp: self.timestamp,
+            operation: Operation::Forex {
+                sold,
+                bought,
+                commission: money(
+                    &self.commission_currency.unwrap_or(currency::Code::USD),
+                    self.commission,
+                ),
+            },
+        })
+    }
+}
+
+impl DividendTax {
+    fn into_adjustment(self, dividend_date: Option<NaiveDate>) -> Activity {
+        Activity {
+            instrument: Instrument {
+                symbol: parse_symbol(&self.description),
+                isin: Instrument::parse_isin(&self.description),
+                ..Default::default()
+            },
+            account: self.account.clone(),
+            timestamp: NaiveDateTime::new(
+                self.timestamp,
+                NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            ),
+            operation: Operation::WithholdingAdjustment {
                 value: Money {
-                    original: currency::new(&dividend.currency, dividend.value.round_dp(2)),
+                    original: currency::new(&self.currency, (-self.value).round_dp(2)),
                     pln: Pln::default(),
                     rate: None,
                 },
-                withholding_tax: Money {
-                    original: currency::new(&tax.currency, tax.value.abs().round_dp(2)),
-                    pln: Pln::default(),
-                    rate: None,
+                dividend_date,
+            },
+        }
+    }
+}
+
+/// Dividend and withholding tax entries are matched by account, symbol, date, currency and
+/// whether they are payments in lieu, so reversals and corrections net out within each group.
+type DividendKey = (String, String, NaiveDate, currency::Code, bool);
+
+fn dividend_key(
+    account: &str,
+    description: &str,
+    timestamp: NaiveDate,
+    currency: currency::Code,
+) -> DividendKey {
+    (
+        account.to_string(),
+        parse_symbol(description),
+        timestamp,
+        currency,
+        description.to_lowercase().contains("payment in lieu"),
+    )
+}
+
+/// Nets dividends and withholding taxes described as "SYMBOL(ISIN) ...", taxes without a
+/// dividend on the same date become withholding adjustments.
+pub fn match_dividends(dividends: Vec<Dividend>, taxes: Vec<DividendTax>) -> Vec<Activity> {
+    let mut groups: Vec<(DividendKey, Dividend, Decimal)> = vec![];
+    for dividend in dividends {
+        let key = dividend_key(
+            &dividend.account,
+            &dividend.description,
+            dividend.timestamp,
+            dividend.currency,
+        );
+        match groups.iter_mut().find(|(other, _, _)| *other == key) {
+            Some((_, group, _)) => group.value += dividend.value,
+            None => groups.push((key, dividend, Decimal::ZERO)),
+        }
+    }
+
+    // Taxes posted on other dates are refunds or corrections of earlier dividends
+    let mut adjustments = vec![];
+    for tax in taxes {
+        let key = dividend_key(&tax.account, &tax.description, tax.timestamp, tax.currency);
+        match groups.iter_mut().find(|(other, _, _)| *other == key) {
+            Some((_, _, value)) => *value += tax.value,
+            None => {
+                let dividend_date = groups
+                    .iter()
+                    .filter(|((account, symbol, date, currency, lieu), dividend, _)| {
+                        (account, symbol, currency, lieu) == (&key.0, &key.1, &key.3, &key.4)
+                            && *date < key.2
+                            && dividend.value > Decimal::ZERO
+                    })
+                    .map(|((_, _, date, _, _), _, _)| *date)
+                    .max();
+                adjustments.push(tax.into_adjustment(dividend_date));
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|((_, _, _, _, lieu), dividend, tax)| {
+            if dividend.value <= Decimal::ZERO {
+                if !dividend.value.is_zero() || !tax.is_zero() {
+                    println!(
+                        "{prefix} Unmatched dividend reversal: {date} {description} {value} with tax {tax}",
+                        prefix = "WARNING".yellow(),
+                        date = dividend.timestamp,
+                        description = dividend.description,
+                        value = dividend.value,
+                    );
+                }
+                return None;
+            }
+
+            let value = Money {
+                original: currency::new(&dividend.currency, dividend.value.round_dp(2)),
+                pln: Pln::default(),
+                rate: None,
+            };
+            let withholding_tax = Money {
+                original: currency::new(
+                    &dividend.currency,
+                    (-tax).max(Decimal::ZERO).round_dp(2),
+                ),
+                pln: Pln::default(),
+                rate: None,
+            };
+
+            Some(Activity {
+                instrument: Instrument {
+                    symbol: parse_symbol(&dividend.description),
+                    isin: Instrument::parse_isin(&dividend.description),
+                    ..Default::default()
                 },
+                account: dividend.account.clone(),
+                timestamp: NaiveDateTime::new(
+                    dividend.timestamp,
+                    NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+                ),
+                operation: match lieu {
+                    true => Operation::PaymentInLieu {
+                        value,
+                        withholding_tax,
+                    },
+                    false => Operation::Dividend {
+                        value,
+                        withholding_tax,
+                        country: parse_country(&dividend.description),
+                    },
+                },
+            })
+        })
+        .chain(adjustments)
+        .collect()
+}
+
+impl TryInto<Activity> for Transfer {
+    type Error = Error;
+
+    fn try_into(self) -> Result<Activity, Self::Error> {
+        let quantity = self.quantity.abs();
+        let operation = match self.direction.as_str() {
+            "In" => Operation::TransferIn {
+                quantity,
+                lots: vec![],
             },
+            "Out" => Operation::TransferOut { quantity },
+            direction => {
+                return Err(Error::new(&format!(
+                    "Unknown transfer direction \"{}\" of {}",
+                    direction, self.symbol
+                )))
+            }
+        };
+
+        Ok(Activity {
+            instrument: Instrument::new(&self.symbol),
+            account: self.account.clone(),
+            timestamp: NaiveDateTime::new(
+                self.timestamp,
+                NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            ),
+            operation,
         })
     }
 }
 
-fn extract<T>(lines: String) -> Result<impl Iterator<Item = T>, Box<dyn error::Error>>
-where
-    T: de::DeserializeOwned,
-{
-    let mut reader = ReaderBuilder::new()
-        .delimiter(b',')
-        .has_headers(true)
-        .flexible(true)
-        .from_reader(lines.as_bytes());
+fn classify_fee(description: &str) -> FeeKind {
+    let description = description.to_uppercase();
+    let custody = [
+        "ADR FEE",
+        "CUSTODY",
+        "ACCOUNT MAINTENANCE",
+        "MINIMUM ACTIVITY",
+    ];
+    let market_data = [
+        "SNAPSHOT",
+        "NETWORK",
+        "MARKET DATA",
+        "OPRA",
+        "QUOTE",
+        "BUNDLE",
+    ];
 
-    let values: Vec<T> = reader.deserialize::<T>().collect::<Result<Vec<_>, _>>()?;
-    Ok(values.into_iter())
+    if custody.iter().any(|text| description.contains(text)) {
+        FeeKind::Custody
+    } else if market_data.iter().any(|text| description.contains(text)) {
+        FeeKind::MarketData
+    } else {
+        FeeKind::Other
+    }
 }
 
-fn filter_lines<F>(lines: &[String], function: F) -> String
-where
-    F: FnMut(&&std::string::String) -> bool,
-{
-    lines
-        .iter()
-        .filter(function)
-        .collect::<Vec<_>>()
-        .iter()
-        .fold(String::new(), |buf, &line| buf + line + "\n")
+impl CashEntry {
+    /// Returns the stock an ADR fee is charged for, or the currency for other entries.
+    fn instrument(&self) -> Instrument {
+        match Instrument::parse_isin(&self.description) {
+            Some(isin) => Instrument {
+                symbol: parse_symbol(&self.description),
+                isin: Some(isin),
+                ..Default::default()
+            },
+            None => Instrument::new(&self.currency.to_string()),
+        }
+    }
+
+    fn money(&self, value: Decimal) -> Money {
+        Money {
+            original: currency::new(&self.currency, value.round_dp(2)),
+            pln: Pln::default(),
+            rate: None,
+        }
+    }
+
+    /// Converts interest entry, withholding tax is matched by currency and interest period.
+    fn into_interest(self, taxes: &mut Vec<CashEntry>) -> Activity {
+        let period = self
+            .description
+            .find("Interest")
+            .map_or("", |index| &self.description[index..]);
+        let tax = taxes
+            .iter()
+            .position(|tax| {
+                tax.account == self.account
+                    && tax.currency == self.currency
+                    && tax.description.ends_with(period)
+            })
+            .map(|index| taxes.remove(index))
+            .map_or(Decimal::ZERO, |tax| tax.value.abs());
+
+        Activity {
+            instrument: self.instrument(),
+            account: self.account.clone(),
+            timestamp: NaiveDateTime::new(
+                self.timestamp,
+                NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            ),
+            operation: match self.value.is_sign_positive() {
+                true => Operation::Interest {
+                    value: self.money(self.value),
+                    withholding_tax: self.money(tax),
+                },
+                false => Operation::InterestPaid {
+                    value: self.money(self.value.abs()),
+                },
+            },
+        }
+    }
+
+    /// Converts fee entry, charges are positive and refunds negative.
+    fn into_fee(self) -> Activity {
+        Activity {
+            instrument: self.instrument(),
+            account: self.account.clone(),
+            timestamp: NaiveDateTime::new(
+                self.timestamp,
+                NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            ),
+            operation: Operation::Fee {
+                value: self.money(-self.value),
+                kind: classify_fee(&self.description),
+                description: self.description,
+            },
+        }
+    }
+}
+
+impl DerivativeInstrument {
+    fn identify(&self, instrument: &mut Instrument) {
+        if let Some(derivative) = &mut instrument.derivative {
+            derivative.underlying = self.underlying.to_string();
+            derivative.multiplier = self.multiplier;
+            derivative.expiry = Some(self.expiry);
+        }
+        if !self.contract_id.is_empty() {
+            instrument.contract_id = Some(self.contract_id.to_string());
+        }
+    }
+}
+
+impl FinancialInstrument {
+    fn identify(&self, instrument: &mut Instrument) {
+        if instrument.isin.is_none() && !self.isin.is_empty() {
+            instrument.isin = Some(self.isin.to_string());
+        }
+        if !self.exchange.is_empty() {
+            instrument.exchange = Some(self.exchange.to_string());
+        }
+        if !self.contract_id.is_empty() {
+            instrument.contract_id = Some(self.contract_id.to_string());
+        }
+    }
+}
+
+impl CorporateAction {
+    fn parse_operation(&self) -> Option<Operation> {
+        let description = &self.description;
+        let renames = [
+            "Symbol Change",
+            "Change of Listing",
+            "Name Change",
+            "CUSIP/ISIN Change",
+        ];
+
+        if let Some((numerator, denominator)) = parse_ratio(description, " Split ") {
+            Some(Operation::Split {
+                numerator,
+                denominator,
+            })
+        } else if description.contains(" Merged") {
+            let (numerator, denominator) = parse_ratio(description, " WITH ")?;
+            Some(Operation::Merger {
+                symbol: parse_target(description)?,
+                isin: parse_target_isin(description),
+                numerator,
+                denominator,
+            })
+        } else if renames.iter().any(|rename| description.contains(rename)) {
+            let symbol = parse_target(description)?;
+            let isin = parse_target_isin(description);
+            match symbol == parse_symbol(description) && isin == Instrument::parse_isin(description)
+            {
+                true => None,
+                false => Some(Operation::Rename { symbol, isin }),
+            }
+        } else {
+            None
+        }
+    }
+
+    fn into_activity(self) -> Option<Activity> {
+        // Statement has no cost allocation, spin-off without it would leave new shares at zero cost
+        if self.description.contains(" Spinoff ") {
+            println!(
+                "{prefix} Spin-off skipped, add it with cost allocation to corporate actions overrides file: {description}",
+                prefix = "WARNING".yellow(),
+                description = self.description,
+            );
+            return None;
+        }
+
+        match self.parse_operation() {
+            Some(operation) => Some(Activity {
+                instrument: Instrument {
+                    symbol: parse_symbol(&self.description),
+                    isin: Instrument::parse_isin(&self.description),
+                    ..Default::default()
+                },
+                account: self.account.clone(),
+                timestamp: self.timestamp,
+                operation,
+            }),
+            None => {
+                println!(
+                    "{prefix} Unsupported corporate action: {description}",
+                    prefix = "WARNING".yellow(),
+                    description = self.description,
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Section of activity statement with its own header, IB repeats sections with different
+/// columns for every asset category or account.
+struct Section {
+    name: String,
+    header: StringRecord,
+    rows: Vec<StringRecord>,
+}
+
+struct Row<'a> {
+    header: &'a StringRecord,
+    record: &'a StringRecord,
+}
+
+impl Row<'_> {
+    fn get(&self, column: &str) -> &str {
+        self.header
+            .iter()
+            .position(|name| name == column)
+            .and_then(|index| self.record.get(index))
+            .unwrap_or_default()
+    }
+}
+
+/// Statement row deserialized by column names, columns which must be present are listed
+/// so that a changed statement format is reported instead of silently skipped.
+trait Record: de::DeserializeOwned {
+    const COLUMNS: &'static [&'static str];
+
+    /// Fills values which cannot be deserialized by fixed column names.
+    fn complete(&mut self, _row: &Row) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Statement {
+    sections: Vec<Section>,
+}
+
+impl Statement {
+    fn read(path: &Path) -> Result<Statement, Box<dyn error::Error>> {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b',')
+            .has_headers(false)
+            .flexible(true)
+            .from_path(path)?;
+
+        let mut sections: Vec<Section> = vec![];
+        for record in reader.records() {
+            let record = record?;
+            let (Some(name), Some(kind)) = (record.get(0), record.get(1)) else {
+                continue;
+            };
+            let name = name.trim_start_matches('\u{feff}');
+            let fields: StringRecord = record.iter().skip(2).collect();
+
+            match kind {
+                "Header" => sections.push(Section {
+                    name: name.to_string(),
+                    header: fields,
+                    rows: vec![],
+                }),
+                // Subtotals per currency and account are data rows starting with "Total"
+                "Data" if !fields.get(0).unwrap_or_default().starts_with("Total") => {
+                    let section = sections
+                        .iter_mut()
+                        .rev()
+                        .find(|section| section.name == name)
+                        .ok_or(Error::new(&format!("Section {} has no header", name)))?;
+                    section.rows.push(fields);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Statement { sections })
+    }
+
+    /// Deserializes rows of all sections with given name accepted by filter.
+    fn extract<T, F>(&self, name: &str, filter: F) -> Result<Vec<T>, Box<dyn error::Error>>
+    where
+        T: Record,
+        F: Fn(&Row) -> bool,
+    {
+        let mut values = vec![];
+        for section in self.sections.iter().filter(|section| section.name == name) {
+            let header = &section.header;
+            let rows: Vec<_> = section
+                .rows
+                .iter()
+                .filter(|record| filter(&Row { header, record }))
+                .collect();
+            if rows.is_empty() {
+                continue;
+            }
+
+            let missing: Vec<_> = T::COLUMNS
+                .iter()
+                .filter(|column| !header.iter().any(|name| name == **column))
+                .copied()
+                .collect();
+            if !missing.is_empty() {
+                return Err(Error::new(&format!(
+                    "Section {} is missing columns: {}",
+                    name,
+                    missing.join(", ")
+                ))
+                .into());
+            }
+
+            for record in rows {
+                let mut value: T = record.deserialize(Some(header))?;
+                value.complete(&Row { header, record })?;
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+}
+
+impl Record for Transaction {
+    const COLUMNS: &'static [&'static str] = &[
+        "Asset Category",
+        "Symbol",
+        "Quantity",
+        "T. Price",
+        "Currency",
+        "Date/Time",
+        "Comm/Fee",
+    ];
+}
+
+impl Record for ForexTransaction {
+    const COLUMNS: &'static [&'static str] = &["Symbol", "Quantity", "Proceeds", "Date/Time"];
+
+    /// Reads commission from "Comm in <base currency>" column.
+    fn complete(&mut self, row: &Row) -> Result<(), Error> {
+        let (column, code) = row
+            .header
+            .iter()
+            .find_map(|name| name.strip_prefix("Comm in ").map(|code| (name, code)))
+            .ok_or(Error::new("Section Trades is missing commission column"))?;
+        self.commission_currency = Some(
+            serde_json::from_value(serde_json::Value::String(code.to_string())).or(Err(
+                Error::new(&format!("Unsupported commission currency {}", code)),
+            ))?,
+        );
+        self.commission = match row.get(column) {
+            "" => Decimal::ZERO,
+            value => Decimal::from_str_exact(value).or(Err(Error::new(&format!(
+                "Failed to parse commission \"{}\" of {}",
+                value, self.symbol
+            ))))?,
+        };
+        Ok(())
+    }
+}
+
+impl Record for Dividend {
+    const COLUMNS: &'static [&'static str] = &["Currency", "Date", "Description", "Amount"];
+}
+
+impl Record for DividendTax {
+    const COLUMNS: &'static [&'static str] = &["Currency", "Date", "Description", "Amount"];
+}
+
+impl Record for CashEntry {
+    const COLUMNS: &'static [&'static str] = &["Currency", "Date", "Description", "Amount"];
+}
+
+impl Record for FinancialInstrument {
+    const COLUMNS: &'static [&'static str] = &["Symbol", "Conid", "Security ID", "Listing Exch"];
+}
+
+impl Record for DerivativeInstrument {
+    const COLUMNS: &'static [&'static str] =
+        &["Symbol", "Conid", "Underlying", "Multiplier", "Expiry"];
+}
+
+impl Record for Transfer {
+    const COLUMNS: &'static [&'static str] = &["Symbol", "Date", "Direction", "Qty"];
+}
+
+impl Record for CorporateAction {
+    const COLUMNS: &'static [&'static str] = &["Date/Time", "Description"];
 }
 
 pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn error::Error>> {
-    let handle = File::open(path)?;
-    let reader = BufReader::new(handle);
-    let lines: Vec<_> = reader.lines().collect::<Result<Vec<_>, _>>()?;
+    let statement = Statement::read(path)?;
+    let categories = ["Stocks", "Equity and Index Options", "Futures"];
 
-    let transactions = filter_lines(&lines, |line| {
-        let header = "Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,C. Price,Proceeds,Comm/Fee,Basis,Realized P/L,MTM P/L,Code";
-        let prefix = "Trades,Data,Order,Stocks";
-        line.starts_with(header) || line.starts_with(prefix)
-    });
+    let mut transactions = statement
+        .extract::<Transaction, _>("Trades", |row| {
+            row.get("DataDiscriminator") == "Order"
+                && categories.contains(&row.get("Asset Category"))
+        })?
+        .into_iter()
+        .map(|entry| entry.into())
+        .collect::<Vec<Activity>>();
+    // Option exercise and assignment must precede the resulting trade of the underlying
+    // reported at the same time, derivatives go first among trades of the same timestamp
+    transactions
+        .sort_by_key(|activity| (activity.timestamp, activity.instrument.derivative.is_none()));
 
-    let transactions = extract::<Transaction>(transactions)?
+    let conversions = statement
+        .extract::<ForexTransaction, _>("Trades", |row| {
+            row.get("DataDiscriminator") == "Order" && row.get("Asset Category") == "Forex"
+        })?
+        .into_iter()
         .map(|entry| entry.try_into())
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter();
+        .collect::<Result<Vec<Activity>, _>>()?;
+
+    let is_interest = |row: &Row| row.get("Description").contains(" Interest ");
+    let dividends = statement.extract::<Dividend, _>("Dividends", |_| true)?;
+    let dividend_taxes =
+        statement.extract::<DividendTax, _>("Withholding Tax", |row| !is_interest(row))?;
+    let dividends = match_dividends(dividends, dividend_taxes);
 
-    let dividends = filter_lines(&lines, |line| {
-        let header = "Dividends,Header,Currency,Date,Description,Amount";
-        let prefix = "Dividends,Data,";
-        let summary_prefix = "Dividends,Data,Total";
-        (line.starts_with(header) || line.starts_with(prefix)) && !line.starts_with(summary_prefix)
-    });
+    let mut interest_taxes = statement.extract::<CashEntry, _>("Withholding Tax", is_interest)?;
+    let interests = statement
+        .extract::<CashEntry, _>("Interest", |_| true)?
+        .into_iter()
+        .map(|entry| entry.into_interest(&mut interest_taxes))
+        .collect::<Vec<_>>();
+    for tax in &interest_taxes {
+        println!(
+            "{prefix} Unmatched interest withholding tax: {description}",
+            prefix = "WARNING".yellow(),
+            description = tax.description,
+        );
+    }
+
+    let fees = statement
+        .extract::<CashEntry, _>("Fees", |_| true)?
+        .into_iter()
+        .chain(statement.extract::<CashEntry, _>("Other Fees", |_| true)?)
+        .map(|entry| entry.into_fee())
+        .collect::<Vec<_>>();
 
-    let dividends = extract::<Dividend>(dividends)?.into_iter();
+    let is_stock = |row: &Row| row.get("Asset Category") == "Stocks";
 
-    let dividend_taxes = filter_lines(&lines, |line| {
-        let header = "Withholding Tax,Header,Currency,Date,Description,Amount,Code";
-        let prefix = "Withholding Tax,";
-        let summary_prefix = "Withholding Tax,Data,Total";
-        (line.starts_with(header) || line.starts_with(prefix)) && !line.starts_with(summary_prefix)
-    });
+    // Actions changing ISIN are reported twice, as removal of old and addition of new shares
+    let mut corporate_actions =
+        statement.extract::<CorporateAction, _>("Corporate Actions", is_stock)?;
+    corporate_actions.dedup_by(|a, b| a.description == b.description && a.timestamp == b.timestamp);
 
-    let dividend_taxes = extract::<DividendTax>(dividend_taxes)?.into_iter();
+    let corporate_actions = corporate_actions
+        .into_iter()
+        .filter_map(|entry| entry.into_activity())
+        .collect::<Vec<_>>();
 
-    let dividends = dividends
-        .zip(dividend_taxes)
+    let transfers = statement
+        .extract::<Transfer, _>("Transfers", is_stock)?
+        .into_iter()
         .map(|entry| entry.try_into())
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter();
+        .collect::<Result<Vec<Activity>, _>>()?;
+
+    let section = "Financial Instrument Information";
+    let instruments = statement.extract::<FinancialInstrument, _>(section, is_stock)?;
+    let derivatives = statement.extract::<DerivativeInstrument, _>(section, |row| {
+        categories[1..].contains(&row.get("Asset Category"))
+    })?;
+
+    let mut activities = vec![]
+        .into_iter()
+        .chain(transactions)
+        .chain(dividends)
+        .chain(corporate_actions)
+        .chain(transfers)
+        .chain(conversions)
+        .chain(interests)
+        .chain(fees)
+        .collect::<Vec<_>>();
+
+    for activity in &mut activities {
+        if let Some(instrument) = instruments
+            .iter()
+            .find(|instrument| instrument.symbol == activity.instrument.symbol)
+        {
+            instrument.identify(&mut activity.instrument);
+        }
+        match derivatives
+            .iter()
+            .find(|derivative| derivative.symbol == activity.instrument.symbol)
+        {
+            Some(derivative) => derivative.identify(&mut activity.instrument),
+            None => match &activity.instrument.derivative {
+                // Contract size of futures differs per contract, cost cannot be guessed
+                Some(derivative) if derivative.kind == DerivativeKind::Future => {
+                    return Err(Box::new(Error::new(&format!(
+                        "Missing multiplier of future {}, statement has no {} of it",
+                        activity.instrument.symbol, section
+                    ))));
+                }
+                Some(derivative) => println!(
+                    "{prefix} No {section} of {symbol}, assuming multiplier {multiplier}",
+                    prefix = "WARNING".yellow(),
+                    symbol = activity.instrument.symbol,
+                    multiplier = derivative.multiplier,
+                ),
+                None => {}
+            },
+        }
+    }
+
+    Ok(activities)
+}
+
+/// Parses Flex Query timestamp, e.g. "20220103;093000", "2022-01-03;09:30:00" or "20220103".
+fn parse_flex_timestamp(value: &str) -> Result<NaiveDateTime, Error> {
+    let formats = ["%Y%m%d;%H%M%S", "%Y-%m-%d;%H:%M:%S", "%Y%m%d %H%M%S"];
+    if let Some(timestamp) = formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    {
+        return Ok(timestamp);
+    }
+
+    let date = value.split(';').next().unwrap_or_default();
+    ["%Y%m%d", "%Y-%m-%d"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(date, format).ok())
+        .map(|date| NaiveDateTime::new(date, NaiveTime::from_hms_opt(0, 0, 0).unwrap()))
+        .ok_or(Error::new(&format!(
+            "Failed to parse timestamp \"{}\"",
+            value
+        )))
+}
+
+/// Attribute of Flex Query element, empty attributes are treated as missing.
+fn flex_optional<'a>(node: &roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attribute(name).filter(|value| !value.is_empty())
+}
+
+fn flex_attribute<'a>(node: &roxmltree::Node<'a, '_>, name: &str) -> Result<&'a str, Error> {
+    flex_optional(node, name).ok_or(Error::new(&format!(
+        "Missing attribute {} of {} {}",
+        name,
+        node.tag_name().name(),
+        node.attribute("symbol").unwrap_or_default()
+    )))
+}
+
+fn flex_decimal(node: &roxmltree::Node, name: &str) -> Result<Decimal, Error> {
+    let value = flex_attribute(node, name)?;
+    value.parse().or(Err(Error::new(&format!(
+        "Failed to parse {} \"{}\" of {}",
+        name,
+        value,
+        node.tag_name().name()
+    ))))
+}
+
+fn flex_currency(node: &roxmltree::Node, name: &str) -> Result<currency::Code, Error> {
+    let code = flex_attribute(node, name)?;
+    serde_json::from_value(serde_json::Value::String(code.to_string())).or(Err(Error::new(
+        &format!(
+            "Unsupported currency {} of {}",
+            code,
+            node.tag_name().name()
+        ),
+    )))
+}
+
+fn flex_timestamp(node: &roxmltree::Node, name: &str) -> Result<NaiveDateTime, Error> {
+    parse_flex_timestamp(flex_attribute(node, name)?)
+}
+
+fn flex_account(node: &roxmltree::Node) -> String {
+    node.attribute("accountId").unwrap_or_default().to_string()
+}
+
+fn flex_derivative(node: &roxmltree::Node) -> Result<Option<Derivative>, Error> {
+    let kind = match flex_attribute(node, "assetCategory")? {
+        "OPT" => DerivativeKind::Option,
+        "FUT" => DerivativeKind::Future,
+        _ => return Ok(None),
+    };
+    let expiry = match flex_optional(node, "expiry") {
+        Some(expiry) => Some(parse_flex_timestamp(expiry)?.date()),
+        None => None,
+    };
+
+    Ok(Some(Derivative {
+        kind,
+        underlying: flex_attribute(node, "underlyingSymbol")?.to_string(),
+        multiplier: flex_decimal(node, "multiplier")?,
+        expiry,
+        strike: flex_optional(node, "strike").and_then(|strike| strike.parse().ok()),
+        right: match flex_optional(node, "putCall") {
+            Some("C") => Some(Right::Call),
+            Some("P") => Some(Right::Put),
+            _ => None,
+        },
+    }))
+}
+
+fn flex_identify(node: &roxmltree::Node, instrument: &mut Instrument) {
+    let attribute = |name| flex_optional(node, name).map(|value| value.to_string());
+    instrument.isin = attribute("isin").or(instrument.isin.take());
+    instrument.contract_id = attribute("conid");
+    instrument.exchange = attribute("listingExchange");
+}
+
+fn flex_trade(node: &roxmltree::Node) -> Result<Option<Activity>, Error> {
+    let category = match flex_attribute(node, "assetCategory")? {
+        "STK" => "Stocks",
+        "OPT" => "Equity and Index Options",
+        "FUT" => "Futures",
+        "CASH" => {
+            let conversion = ForexTransaction {
+                account: flex_account(node),
+                symbol: flex_attribute(node, "symbol")?.to_string(),
+                quantity: flex_decimal(node, "quantity")?,
+                proceeds: flex_decimal(node, "proceeds")?,
+                timestamp: flex_timestamp(node, "dateTime")?,
+                commission: flex_decimal(node, "ibCommission")?,
+                commission_currency: Some(flex_currency(node, "ibCommissionCurrency")?),
+            };
+            return conversion.try_into().map(Some);
+        }
+        category => {
+            println!(
+                "{prefix} Unsupported asset category {category} of {symbol}",
+                prefix = "WARNING".yellow(),
+                symbol = node.attribute("symbol").unwrap_or_default(),
+            );
+            return Ok(None);
+        }
+    };
+
+    let transaction = Transaction {
+        account: flex_account(node),
+        category: category.to_string(),
+        symbol: flex_attribute(node, "symbol")?.to_string(),
+        quantity: flex_decimal(node, "quantity")?,
+        price: flex_decimal(node, "tradePrice")?,
+        currency: flex_currency(node, "currency")?,
+        timestamp: flex_timestamp(node, "dateTime")?,
+        commission: flex_decimal(node, "ibCommission")?,
+        code: node.attribute("notes").unwrap_or_default().to_string(),
+        derivative: flex_derivative(node)?,
+    };
+
+    let mut activity: Activity = transaction.into();
+    flex_identify(node, &mut activity.instrument);
+    Ok(Some(activity))
+}
+
+/// Converts Flex Query XML with trades, cash transactions, corporate actions and transfers.
+/// Reported FX rates are ignored, values are converted with NBP rates like other sources.
+pub fn convert_flex(path: &Path) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let document = roxmltree::Document::parse(&text)?;
+    let elements = |name: &'static str| {
+        document.descendants().filter(move |node| {
+            node.has_tag_name(name) && node.attribute("levelOfDetail") != Some("SUMMARY")
+        })
+    };
+
+    let mut transactions = vec![];
+    for node in elements("Trade") {
+        transactions.extend(flex_trade(&node)?);
+    }
+    // Option exercise and assignment must precede the resulting trade of the underlying
+    // reported at the same time, derivatives go first among trades of the same timestamp
+    transactions
+        .sort_by_key(|activity| (activity.timestamp, activity.instrument.derivative.is_none()));
+
+    let mut dividends = vec![];
+    let mut dividend_taxes = vec![];
+    let mut interests = vec![];
+    let mut interest_taxes = vec![];
+    let mut fees = vec![];
+    for node in elements("CashTransaction") {
+        let account = flex_account(&node);
+        let description = flex_attribute(&node, "description")?.to_string();
+        let value = flex_decimal(&node, "amount")?;
+        let currency = flex_currency(&node, "currency")?;
+        let timestamp = flex_timestamp(&node, "dateTime")?.date();
+        let entry = CashEntry {
+            account: account.clone(),
+            description: description.clone(),
+            value,
+            currency,
+            timestamp,
+        };
+
+        match flex_attribute(&node, "type")? {
+            "Dividends" | "Payment In Lieu Of Dividends" => dividends.push(Dividend {
+                account,
+                description,
+                value,
+                currency,
+                timestamp,
+            }),
+            "Withholding Tax" if description.contains(" Interest ") => interest_taxes.push(entry),
+            "Withholding Tax" => dividend_taxes.push(DividendTax {
+                account,
+                description,
+                value,
+                currency,
+                timestamp,
+            }),
+            "Broker Interest Received" | "Broker Interest Paid" | "Bond Interest Received" => {
+                interests.push(entry)
+            }
+            "Other Fees" | "Broker Fees" => fees.push(entry.into_fee()),
+            _ => {}
+        }
+    }
+
+    let interests = interests
+        .into_iter()
+        .map(|entry| entry.into_interest(&mut interest_taxes))
+        .collect::<Vec<_>>();
+    for tax in &interest_taxes {
+        println!(
+            "{prefix} Unmatched interest withholding tax: {description}",
+            prefix = "WARNING".yellow(),
+            description = tax.description,
+        );
+    }
+    let dividends = match_dividends(dividends, dividend_taxes);
+
+    let mut corporate_actions = vec![];
+    for node in elements("CorporateAction") {
+        if node.attribute("assetCategory") != Some("STK") {
+            continue;
+        }
+        corporate_actions.push(CorporateAction {
+            account: flex_account(&node),
+            description: flex_attribute(&node, "description")?.to_string(),
+            timestamp: flex_timestamp(&node, "dateTime")?,
+        });
+    }
+
+    // Actions changing ISIN are reported twice, as removal of old and addition of new shares
+    corporate_actions.dedup_by(|a, b| a.description == b.description && a.timestamp == b.timestamp);
+    let corporate_actions = corporate_actions
+        .into_iter()
+        .filter_map(|entry| entry.into_activity())
+        .collect::<Vec<_>>();
+
+    let mut transfers = vec![];
+    for node in elements("Transfer") {
+        if node.attribute("assetCategory") != Some("STK") {
+            continue;
+        }
+        let transfer = Transfer {
+            account: flex_account(&node),
+            symbol: flex_attribute(&node, "symbol")?.to_string(),
+            timestamp: flex_timestamp(&node, "date")?.date(),
+            direction: match flex_attribute(&node, "direction")? {
+                "IN" => "In".to_string(),
+                "OUT" => "Out".to_string(),
+                direction => direction.to_string(),
+            },
+            quantity: flex_decimal(&node, "quantity")?,
+        };
+        let mut activity: Activity = transfer.try_into()?;
+        flex_identify(&node, &mut activity.instrument);
+        transfers.push(activity);
+    }
 
     Ok(vec![]
         .into_iter()
         .chain(transactions)
         .chain(dividends)
-        .collect::<Vec<_>>())
+        .chain(corporate_actions)
+        .chain(transfers)
+        .chain(interests)
+        .chain(fees)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_derivatives() {
+        let statement = "\
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,C. Price,Proceeds,Comm/Fee,Basis,Realized P/L,MTM P/L,Code
+Trades,Data,Order,Stocks,USD,AAPL,\"2022-01-21, 16:20:00\",100,150,170,-15000,0,15000,0,2000,A
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,C. Price,Proceeds,Comm/Fee,Basis,Realized P/L,MTM P/L,Code
+Trades,Data,Order,Equity and Index Options,USD,AAPL 21JAN22 150 C,\"2022-01-21, 16:20:00\",-1,0,0,0,0,-551.05,0,0,A;C
+Trades,Data,Order,Equity and Index Options,USD,AAPL 21JAN22 200 C,\"2022-01-21, 16:20:00\",2,0,0,0,0,247.9,247.9,0,C;Ep
+Trades,Data,Order,Futures,USD,ESH2,\"2022-01-03, 10:00:00\",1,4700,4710,0,-2.25,0,0,500,O
+Financial Instrument Information,Header,Asset Category,Symbol,Description,Conid,Underlying,Listing Exch,Multiplier,Expiry,Delivery Month,Type,Strike,Code
+Financial Instrument Information,Data,Equity and Index Options,AAPL 21JAN22 150 C,AAPL 21JAN22 150 Call,1234,AAPL,CBOE,100,2022-01-21,2022-01,C,150,
+Financial Instrument Information,Header,Asset Category,Symbol,Description,Conid,Underlying,Listing Exch,Multiplier,Expiry,Delivery Month,Code
+Financial Instrument Information,Data,Futures,ESH2,ES 18MAR22,5678,ES,CME,50,2022-03-18,2022-03,
+";
+        let path = env::temp_dir().join("stock_tax_test_derivatives.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 4);
+        assert!(matches!(
+            activities[1].operation,
+            Operation::Assignment { .. }
+        ));
+        assert!(matches!(activities[2].operation, Operation::Expiry { .. }));
+        assert!(matches!(activities[3].operation, Operation::Buy { .. }));
+
+        let option = activities[1].instrument.derivative.as_ref().unwrap();
+        assert_eq!(option.underlying, "AAPL");
+        assert_eq!(option.right, Some(Right::Call));
+        assert_eq!(option.strike, Some(Decimal::from(150)));
+        assert_eq!(option.expiry, NaiveDate::from_ymd_opt(2022, 1, 21));
+
+        let future = activities[0].instrument.derivative.as_ref().unwrap();
+        assert_eq!(future.kind, DerivativeKind::Future);
+        assert_eq!(future.underlying, "ES");
+        assert_eq!(future.multiplier, Decimal::from(50));
+    }
+
+    #[test]
+    fn test_future_without_multiplier() {
+        let statement = "\
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,C. Price,Proceeds,Comm/Fee,Basis,Realized P/L,MTM P/L,Code
+Trades,Data,Order,Futures,USD,ESH2,\"2022-01-03, 10:00:00\",1,4700,4710,0,-2.25,0,0,500,O
+";
+        let path = env::temp_dir().join("stock_tax_test_future_without_multiplier.csv");
+        fs::write(&path, statement).unwrap();
+        let result = convert(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_forex() {
+        let statement = "\
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,,Proceeds,Comm in USD,,,MTM in USD,Code
+Trades,Data,Order,Forex,PLN,USD.PLN,\"2022-01-03, 10:00:00\",1000,4.05,,-4050,-2,,,5,
+Trades,Data,Order,Forex,PLN,USD.PLN,\"2022-02-03, 10:00:00\",-500,4.1,,2050,-2,,,1,
+";
+        let path = env::temp_dir().join("stock_tax_test_forex.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 2);
+        let Operation::Forex { sold, bought, .. } = &activities[0].operation else {
+            panic!("Expected forex conversion");
+        };
+        assert_eq!(sold.original.get_code(), currency::Code::PLN);
+        assert_eq!(*sold.original.get_value(), Decimal::from(4050));
+        assert_eq!(bought.original.get_code(), currency::Code::USD);
+        assert_eq!(*bought.original.get_value(), Decimal::from(1000));
+
+        let Operation::Forex { sold, .. } = &activities[1].operation else {
+            panic!("Expected forex conversion");
+        };
+        assert_eq!(sold.original.get_code(), currency::Code::USD);
+    }
+
+    #[test]
+    fn test_forex_base_currency() {
+        let statement = "\
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,T. Price,,Proceeds,Comm in EUR,,,MTM in EUR,Code
+Trades,Data,Order,Forex,USD,EUR.USD,\"2022-01-03, 10:00:00\",-1000,1.13,,1130,-1.7,,,2,
+";
+        let path = env::temp_dir().join("stock_tax_test_forex_base_currency.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 1);
+        let Operation::Forex { commission, .. } = &activities[0].operation else {
+            panic!("Expected forex conversion");
+        };
+        assert_eq!(commission.original.get_code(), currency::Code::EUR);
+        assert_eq!(*commission.original.get_value(), Decimal::new(17, 1));
+    }
+
+    #[test]
+    fn test_statement_columns() {
+        let statement = "\
+Statement,Header,Field Name,Field Value
+Statement,Data,Title,Activity Statement
+Dividends,Header,Date,Currency,Amount,Description,Code,Added Column
+Dividends,Data,2022-05-12,USD,2.3,AAPL(US0378331005) Cash Dividend USD 0.23 per Share (Ordinary Dividend),,x
+Dividends,Data,Total,,2.3,,,
+";
+        let path = env::temp_dir().join("stock_tax_test_statement_columns.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 1);
+        assert!(matches!(
+            &activities[0].operation,
+            Operation::Dividend { value, .. } if *value.original.get_value() == dec!(2.3)
+        ));
+
+        let statement = "\
+Trades,Header,DataDiscriminator,Asset Category,Currency,Symbol,Date/Time,Quantity,Price,Proceeds,Commission,Code
+Trades,Data,Order,Stocks,USD,AAPL,\"2022-01-03, 10:00:00\",10,150,-1500,-1,O
+";
+        let path = env::temp_dir().join("stock_tax_test_statement_missing_columns.csv");
+        fs::write(&path, statement).unwrap();
+        let error = convert(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "Section Trades is missing columns: T. Price, Comm/Fee"
+        );
+    }
+
+    #[test]
+    fn test_flex_query() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/interactive_brokers_flex.xml");
+        let activities = convert_flex(&path).unwrap();
+
+        assert_eq!(activities.len(), 9);
+        assert!(activities
+            .iter()
+            .all(|activity| activity.account == "U1111111"));
+
+        assert!(matches!(
+            &activities[0].operation,
+            Operation::Forex { commission, .. }
+                if commission.original.get_code() == currency::Code::USD
+        ));
+        let buy = &activities[1];
+        assert!(matches!(
+            &buy.operation,
+            Operation::Buy { quantity, .. } if *quantity == dec!(10)
+        ));
+        assert_eq!(buy.instrument.isin.as_deref(), Some("US0378331005"));
+        assert_eq!(buy.instrument.contract_id.as_deref(), Some("265598"));
+
+        let option = &activities[2];
+        assert!(matches!(option.operation, Operation::Expiry { .. }));
+        let derivative = option.instrument.derivative.as_ref().unwrap();
+        assert_eq!(derivative.underlying, "AAPL");
+        assert_eq!(derivative.multiplier, dec!(100));
+        assert_eq!(derivative.right, Some(Right::Call));
+        assert_eq!(derivative.expiry, NaiveDate::from_ymd_opt(2022, 1, 21));
+
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Sell { quantity, .. } if *quantity == dec!(4)
+        ));
+
+        let Operation::Dividend {
+            value,
+            withholding_tax,
+            country,
+        } = &activities[4].operation
+        else {
+            panic!("Expected dividend");
+        };
+        assert_eq!(*value.original.get_value(), dec!(2.3));
+        assert_eq!(*withholding_tax.original.get_value(), dec!(0.35));
+        assert_eq!(country.as_deref(), Some("US"));
+
+        assert!(matches!(
+            &activities[5].operation,
+            Operation::Split { numerator, denominator }
+                if *numerator == dec!(4) && *denominator == dec!(1)
+        ));
+        assert!(matches!(
+            &activities[6].operation,
+            Operation::TransferIn { quantity, .. } if *quantity == dec!(5)
+        ));
+        assert_eq!(
+            activities[6].instrument.isin.as_deref(),
+            Some("US5949181045")
+        );
+        assert!(matches!(
+            &activities[7].operation,
+            Operation::Interest { .. }
+        ));
+        assert!(matches!(
+            &activities[8].operation,
+            Operation::Fee {
+                kind: FeeKind::MarketData,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_consolidated_statement() {
+        let statement = "\
+Trades,Header,DataDiscriminator,Asset Category,Currency,Account,Symbol,Date/Time,Quantity,T. Price,C. Price,Proceeds,Comm/Fee,Basis,Realized P/L,MTM P/L,Code
+Trades,Data,Order,Stocks,USD,U1111111,AAPL,\"2022-01-03, 10:00:00\",10,150,151,-1500,-1,1501,0,10,O
+Trades,Data,Order,Stocks,USD,U2222222,AAPL,\"2022-01-04, 10:00:00\",5,155,151,-775,-1,776,0,-20,O
+Trades,SubTotal,,Stocks,USD,,AAPL,,15,,,-2275,-2,2277,0,-10,
+Trades,Data,Order,Stocks,EUR,U2222222,SAP,\"2022-01-05, 10:00:00\",-2,120,121,240,-1,-200,39,-2,C
+Trades,SubTotal,,Stocks,EUR,,SAP,,-2,,,240,-1,-200,39,-2,
+Trades,Total,,Stocks,,,,,,,,,,,,,
+Dividends,Header,Currency,Account,Date,Description,Amount
+Dividends,Data,USD,U1111111,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share (Ordinary Dividend),2.3
+Dividends,Data,USD,U2222222,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share (Ordinary Dividend),1.15
+Dividends,Data,Total,,,,3.45
+Dividends,Data,Total in USD,,,,3.45
+Withholding Tax,Header,Currency,Account,Date,Description,Amount,Code
+Withholding Tax,Data,USD,U1111111,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share - US Tax,-0.35,
+Withholding Tax,Data,USD,U2222222,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share - US Tax,-0.17,
+Withholding Tax,Data,Total,,,,-0.52,
+";
+        let path = env::temp_dir().join("stock_tax_test_consolidated_statement.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let accounts = activities
+            .iter()
+            .map(|activity| {
+                (
+                    activity.instrument.symbol.as_str(),
+                    activity.account.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            accounts,
+            [
+                ("AAPL", "U1111111"),
+                ("AAPL", "U2222222"),
+                ("SAP", "U2222222"),
+                ("AAPL", "U1111111"),
+                ("AAPL", "U2222222"),
+            ]
+        );
+        let Operation::Dividend {
+            withholding_tax, ..
+        } = &activities[4].operation
+        else {
+            panic!("Expected dividend");
+        };
+        assert_eq!(*withholding_tax.original.get_value(), dec!(0.17));
+    }
+
+    #[test]
+    fn test_dividend_reversal() {
+        let statement = "\
+Dividends,Header,Currency,Date,Description,Amount
+Dividends,Data,USD,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share (Ordinary Dividend),23
+Dividends,Data,USD,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share (Ordinary Dividend),-23
+Dividends,Data,USD,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.22 per Share (Ordinary Dividend),22
+Dividends,Data,USD,2022-05-12,AAPL(US0378331005) Payment in Lieu of Dividend (Ordinary Dividend),2.2
+Dividends,Data,USD,2022-06-01,MSFT(US5949181045) Cash Dividend USD 0.62 per Share (Ordinary Dividend),6.2
+Dividends,Data,Total,,,30.4
+Withholding Tax,Header,Currency,Date,Description,Amount,Code
+Withholding Tax,Data,USD,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share - US Tax,-3.45,
+Withholding Tax,Data,USD,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.23 per Share - US Tax,3.45,
+Withholding Tax,Data,USD,2022-05-12,AAPL(US0378331005) Cash Dividend USD 0.22 per Share - US Tax,-3.3,
+Withholding Tax,Data,USD,2022-05-12,AAPL(US0378331005) Payment in Lieu of Dividend - US Tax,-0.33,
+Withholding Tax,Data,USD,2022-08-10,AAPL(US0378331005) Cash Dividend USD 0.22 per Share - US Tax,1.1,
+Withholding Tax,Data,USD,2022-08-10,KO(US1912161007) Cash Dividend USD 0.44 per Share - US Tax,0.66,
+Withholding Tax,Data,Total,,,-1.87,
+";
+        let path = env::temp_dir().join("stock_tax_test_dividend_reversal.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let dividends = activities
+            .iter()
+            .filter_map(|activity| match &activity.operation {
+                Operation::Dividend {
+                    value,
+                    withholding_tax,
+                    ..
+                } => Some((
+                    activity.instrument.symbol.as_str(),
+                    *value.original.get_value(),
+                    *withholding_tax.original.get_value(),
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dividends,
+            [("AAPL", dec!(22), dec!(3.3)), ("MSFT", dec!(6.2), dec!(0))]
+        );
+        // Payments in lieu are kept apart so that their tax is not credited as dividend tax
+        assert!(activities.iter().any(|activity| matches!(
+            &activity.operation,
+            Operation::PaymentInLieu { value, withholding_tax }
+                if *value.original.get_value() == dec!(2.2)
+                    && *withholding_tax.original.get_value() == dec!(0.33)
+        )));
+
+        let adjustments = activities
+            .iter()
+            .filter_map(|activity| match &activity.operation {
+                Operation::WithholdingAdjustment {
+                    value,
+                    dividend_date,
+                } => Some((*value.original.get_value(), *dividend_date)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            adjustments,
+            [
+                (dec!(-1.1), NaiveDate::from_ymd_opt(2022, 5, 12)),
+                (dec!(-0.66), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interest_and_fees() {
+        let statement = "\
+Interest,Header,Currency,Date,Description,Amount
+Interest,Data,USD,2022-02-03,USD Credit Interest for Jan-2022,10.5
+Interest,Data,USD,2022-02-03,USD Debit Interest for Jan-2022,-2
+Interest,Data,Total,,,8.5
+Withholding Tax,Header,Currency,Date,Description,Amount,Code
+Withholding Tax,Data,USD,2022-02-03,Withholding @ 20% on Credit Interest for Jan-2022,-2.1,
+Withholding Tax,Data,Total,,,-2.1,
+Fees,Header,Subtitle,Currency,Date,Description,Amount
+Fees,Data,Other Fees,USD,2022-02-03,\"P*****7:NYSE NETWORK A (NP,L1) FOR JAN 2022\",-1.5
+Fees,Data,Total,,,,-1.5
+Other Fees,Header,Currency,Date,Description,Amount
+Other Fees,Data,USD,2022-03-15,BABA(US01609W1027) ADR Fee USD 0.01 PER SHARE,-0.5
+Other Fees,Data,Total,,,-0.5
+";
+        let path = env::temp_dir().join("stock_tax_test_interest_and_fees.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 4);
+        let Operation::Interest {
+            value,
+            withholding_tax,
+        } = &activities[0].operation
+        else {
+            panic!("Expected interest");
+        };
+        assert_eq!(*value.original.get_value(), dec!(10.5));
+        assert_eq!(*withholding_tax.original.get_value(), dec!(2.1));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::InterestPaid { value } if *value.original.get_value() == dec!(2)
+        ));
+        assert!(matches!(
+            &activities[2].operation,
+            Operation::Fee { kind: FeeKind::MarketData, value, .. }
+                if *value.original.get_value() == dec!(1.5)
+        ));
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Fee {
+                kind: FeeKind::Custody,
+                ..
+            }
+        ));
+        assert_eq!(activities[3].instrument.symbol, "BABA");
+        assert_eq!(
+            activities[3].instrument.isin.as_deref(),
+            Some("US01609W1027")
+        );
+        assert_eq!(activities[2].instrument.symbol, "USD");
+    }
 }
@@ -0,0 +1,191 @@
+use crate::currency::{Builder, Pln};
+use clap::ValueEnum;
+use colored::Colorize;
+use derive_more::Display;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+
+const DEDUCTION_YEARS: i32 = 5;
+const LUMP_LIMIT: i64 = 5_000_000;
+
+#[derive(Display, Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Strategy {
+    #[display(fmt = "none")]
+    None,
+    #[display(fmt = "50% per year")]
+    Half,
+    #[display(fmt = "5M PLN lump sum")]
+    Lump,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Loss {
+    year: i32,
+    amount: Pln,
+    #[serde(default)]
+    deducted: Pln,
+    #[serde(default)]
+    lump_used: bool,
+    #[serde(skip)]
+    expiry_reported: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    losses: Vec<Loss>,
+    strategy: Strategy,
+}
+
+fn lesser(a: Pln, b: Pln) -> Pln {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+impl Loss {
+    fn remaining(&self) -> Pln {
+        self.amount - self.deducted
+    }
+
+    fn last_year(&self) -> i32 {
+        self.year + DEDUCTION_YEARS
+    }
+
+    fn is_deductible(&self, year: i32) -> bool {
+        year > self.year && year <= self.last_year() && self.remaining() > Pln::default()
+    }
+}
+
+impl Ledger {
+    pub fn new(strategy: Strategy, losses: Vec<Loss>) -> Ledger {
+        Ledger { losses, strategy }
+    }
+
+    pub fn add(&mut self, year: i32, amount: Pln) {
+        if amount > Pln::default() {
+            self.losses.push(Loss {
+                year,
+                amount,
+                deducted: Pln::default(),
+                lump_used: false,
+                expiry_reported: false,
+            });
+        }
+    }
+
+    /// Deducts losses from previous years from given income, oldest losses first.
+    pub fn deduct(&mut self, year: i32, income: Pln) -> Pln {
+        let mut available = income;
+        let mut total = Pln::default();
+
+        self.losses.sort_by_key(|loss| loss.year);
+        for loss in self.losses.iter_mut() {
+            if !loss.is_deductible(year) || available <= Pln::default() {
+                continue;
+            }
+
+            let limit = match self.strategy {
+                Strategy::None => Pln::default(),
+                Strategy::Lump if !loss.lump_used => {
+                    loss.lump_used = true;
+                    Pln::new(LUMP_LIMIT)
+                }
+                _ => loss.amount * dec!(0.5),
+            };
+
+            let deduction = lesser(lesser(limit, loss.remaining()), available);
+
+            if deduction > Pln::default() {
+                println!(
+                    "{year}: {prefix} from {origin}: {deduction} remaining: {remaining}",
+                    prefix = "Loss deduction".cyan(),
+                    origin = loss.year,
+                    remaining = loss.remaining() - deduction,
+                );
+            }
+
+            loss.deducted += deduction;
+            available = available - deduction;
+            total += deduction;
+        }
+
+        total
+    }
+
+    /// Warns about losses which can be deducted for the last time in given year or already expired.
+    pub fn warn(&mut self, year: i32) {
+        for loss in self.losses.iter_mut() {
+            if loss.remaining() <= Pln::default() || loss.expiry_reported {
+                continue;
+            }
+
+            if loss.last_year() == year {
+                println!(
+                    "{year}: {prefix} loss from {origin}: {remaining} can be deducted for the last time",
+                    prefix = "WARNING".yellow(),
+                    origin = loss.year,
+                    remaining = loss.remaining(),
+                );
+            } else if loss.last_year() < year {
+                println!(
+                    "{year}: {prefix} loss from {origin}: {remaining} expired in {last_year}",
+                    prefix = "WARNING".yellow(),
+                    origin = loss.year,
+                    remaining = loss.remaining(),
+                    last_year = loss.last_year(),
+                );
+                loss.expiry_reported = true;
+            }
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_deduction() {
+        let mut ledger = Ledger::new(Strategy::Half, vec![]);
+        ledger.add(2020, Pln::new(1000));
+
+        assert_eq!(ledger.deduct(2020, Pln::new(5000)), Pln::default());
+        assert_eq!(ledger.deduct(2021, Pln::new(5000)), Pln::new(500));
+        assert_eq!(ledger.deduct(2022, Pln::new(200)), Pln::new(200));
+        assert_eq!(ledger.deduct(2023, Pln::new(5000)), Pln::new(300));
+        assert_eq!(ledger.deduct(2024, Pln::new(5000)), Pln::default());
+    }
+
+    #[test]
+    fn test_lump_deduction() {
+        let mut ledger = Ledger::new(Strategy::Lump, vec![]);
+        ledger.add(2020, Pln::new(1000));
+        ledger.add(2021, Pln::new(400));
+
+        assert_eq!(ledger.deduct(2022, Pln::new(1200)), Pln::new(1200));
+        assert_eq!(ledger.deduct(2023, Pln::new(1000)), Pln::new(200));
+    }
+
+    #[test]
+    fn test_expired_loss() {
+        let mut ledger = Ledger::new(Strategy::Half, vec![]);
+        ledger.add(2015, Pln::new(1000));
+
+        assert_eq!(ledger.deduct(2020, Pln::new(100)), Pln::new(100));
+        assert_eq!(ledger.deduct(2021, Pln::new(1000)), Pln::default());
+    }
+
+    #[test]
+    fn test_no_deduction() {
+        let mut ledger = Ledger::new(Strategy::None, vec![]);
+        ledger.add(2020, Pln::new(1000));
+
+        assert_eq!(ledger.deduct(2021, Pln::new(1000)), Pln::default());
+    }
+}
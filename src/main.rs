@@ -1,13 +1,27 @@
 use clap::{Parser, Subcommand};
 
 mod activity;
+mod bossa;
 mod compute;
 mod convert;
 mod currency;
+mod degiro;
+mod equity_plan;
+mod holdings;
+mod identity;
 mod interactive_brokers;
+mod loss;
 mod mbank;
 mod nbp;
+mod pekao;
+mod pko;
+mod reconcile;
+mod revolut;
+mod simulate;
 mod tax;
+mod trading212;
+mod treaty;
+mod xtb;
 
 #[derive(Parser)]
 struct Cli {
@@ -19,6 +33,8 @@ struct Cli {
 enum Command {
     Convert(convert::CommandArgs),
     Compute(compute::CommandArgs),
+    Holdings(holdings::CommandArgs),
+    Simulate(simulate::CommandArgs),
 }
 
 fn main() {
@@ -27,6 +43,8 @@ fn main() {
     let result = match &cli.command {
         Command::Convert(args) => convert::command(&args),
         Command::Compute(args) => compute::command(&args),
+        Command::Holdings(args) => holdings::command(args),
+        Command::Simulate(args) => simulate::command(args),
     };
 
     match result {
@@ -1,13 +1,15 @@
-use crate::activity::{self, Activity, Money};
+use crate::activity::{self, Activity, Instrument, Money};
 use crate::currency;
 use crate::currency::Pln;
 use chrono::NaiveDateTime;
 use csv::ReaderBuilder;
 use derive_more::Display;
+use encoding_rs::WINDOWS_1250;
 use rust_decimal::Decimal;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::Into;
 use std::error::Error;
+use std::fs;
 use std::path::Path;
 
 #[derive(Debug, Deserialize, Serialize, Display)]
@@ -61,12 +63,21 @@ where
     }
 }
 
-fn from_float<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+/// Parses number with decimal comma and optional thousands separators, e.g. "1 234,50",
+/// empty number is zero.
+pub fn from_float<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
 where
     D: Deserializer<'de>,
 {
     let price: &str = Deserialize::deserialize(deserializer)?;
-    let price = price.trim().replace(",", ".");
+    let price: String = price
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .replace(",", ".");
+    if price.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
 
     match Decimal::from_str_exact(&price) {
         Ok(price) => Ok(price),
@@ -100,7 +111,8 @@ where
 impl Into<Activity> for Transaction {
     fn into(self) -> Activity {
         Activity {
-            symbol: self.symbol,
+            instrument: Instrument::new(&self.symbol),
+            account: String::new(),
             timestamp: self.timestamp,
             operation: match self.operation {
                 Operation::Buy => activity::Operation::Buy {
@@ -140,16 +152,70 @@ impl Into<Activity> for Transaction {
     }
 }
 
-pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn Error>> {
+/// Trade from history export of a Polish broker, price is kept as reported and commission
+/// is rounded to the smallest unit of its currency.
+pub struct Trade {
+    pub instrument: Instrument,
+    pub timestamp: NaiveDateTime,
+    pub buy: bool,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    pub currency: currency::Code,
+    pub commission: Decimal,
+    pub commission_currency: currency::Code,
+}
+
+impl From<Trade> for Activity {
+    fn from(trade: Trade) -> Activity {
+        let price = Money {
+            original: currency::new(&trade.currency, trade.price),
+            pln: Pln::default(),
+            rate: None,
+        };
+        let commission = Money {
+            original: currency::new(&trade.commission_currency, trade.commission.round_dp(2)),
+            pln: Pln::default(),
+            rate: None,
+        };
+
+        Activity {
+            instrument: trade.instrument,
+            account: String::new(),
+            timestamp: trade.timestamp,
+            operation: match trade.buy {
+                true => activity::Operation::Buy {
+                    quantity: trade.quantity,
+                    price,
+                    commission,
+                },
+                false => activity::Operation::Sell {
+                    quantity: trade.quantity,
+                    price,
+                    commission,
+                },
+            },
+        }
+    }
+}
+
+/// Reads semicolon delimited history export of a Polish broker, exports which are not UTF-8
+/// are decoded as Windows-1250.
+pub fn read_transactions<T: de::DeserializeOwned>(path: &Path) -> Result<Vec<T>, Box<dyn Error>> {
+    let text = match String::from_utf8(fs::read(path)?) {
+        Ok(text) => text,
+        Err(error) => WINDOWS_1250.decode(error.as_bytes()).0.into_owned(),
+    };
     let mut reader = ReaderBuilder::new()
         .delimiter(b';')
         .has_headers(true)
         .flexible(true)
-        .from_path(path)?;
+        .from_reader(text.trim_start_matches('\u{feff}').as_bytes());
+
+    Ok(reader.deserialize::<T>().collect::<Result<Vec<_>, _>>()?)
+}
 
-    let transactions = reader
-        .deserialize::<Transaction>()
-        .collect::<Result<Vec<_>, _>>()?;
+pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let transactions = read_transactions::<Transaction>(path)?;
 
     Ok(transactions
         .into_iter()
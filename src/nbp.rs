@@ -7,6 +7,7 @@ use reqwest::{blocking::Client, StatusCode};
 use rust_decimal::Decimal;
 use serde::{de, Deserialize, Deserializer, Serialize};
 
+use std::fmt;
 use std::iter;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -110,6 +111,12 @@ where
         .map_err(|_| de::Error::custom(format!("Failed to parse date: {}", value)))
 }
 
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} {})", self.value, self.id, self.date)
+    }
+}
+
 impl Into<Rate> for Entry {
     fn into(self) -> Rate {
         Rate {
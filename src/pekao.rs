@@ -0,0 +1,141 @@
+use crate::activity::{Activity, Instrument};
+use crate::currency;
+use crate::mbank::{from_float, read_transactions, Trade};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error::Error;
+use std::path::Path;
+
+/// Row of Pekao DM transaction history, date and time are reported in separate columns.
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(
+        rename(deserialize = "Data transakcji"),
+        deserialize_with = "from_date"
+    )]
+    date: NaiveDate,
+    #[serde(
+        rename(deserialize = "Godzina"),
+        deserialize_with = "from_time",
+        default
+    )]
+    time: Option<NaiveTime>,
+    #[serde(rename(deserialize = "Instrument"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Kod ISIN"), default)]
+    isin: Option<String>,
+    #[serde(rename(deserialize = "Rodzaj"))]
+    operation: Operation,
+    #[serde(rename(deserialize = "Ilość"), deserialize_with = "from_float")]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Cena"), deserialize_with = "from_float")]
+    price: Decimal,
+    #[serde(rename(deserialize = "Prowizja"), deserialize_with = "from_float")]
+    commission: Decimal,
+    #[serde(rename(deserialize = "Waluta"), default)]
+    currency: Option<currency::Code>,
+}
+
+#[derive(Debug, Deserialize)]
+enum Operation {
+    #[serde(rename(deserialize = "Kupno"))]
+    Buy,
+    #[serde(rename(deserialize = "Sprzedaż"))]
+    Sell,
+}
+
+fn from_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let date: &str = Deserialize::deserialize(deserializer)?;
+    match NaiveDate::parse_from_str(date, "%d.%m.%Y") {
+        Ok(date) => Ok(date),
+        _ => Err(de::Error::custom(format!("Failed to parse \"{}\"", date))),
+    }
+}
+
+fn from_time<'de, D>(deserializer: D) -> Result<Option<NaiveTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time: &str = Deserialize::deserialize(deserializer)?;
+    if time.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match NaiveTime::parse_from_str(time, "%H:%M:%S") {
+        Ok(time) => Ok(Some(time)),
+        _ => Err(de::Error::custom(format!("Failed to parse \"{}\"", time))),
+    }
+}
+
+impl From<Transaction> for Activity {
+    fn from(transaction: Transaction) -> Activity {
+        let currency = transaction.currency.unwrap_or(currency::Code::PLN);
+        Trade {
+            instrument: Instrument {
+                symbol: transaction.symbol,
+                isin: transaction.isin.filter(|isin| !isin.is_empty()),
+                ..Default::default()
+            },
+            timestamp: NaiveDateTime::new(
+                transaction.date,
+                transaction.time.unwrap_or(NaiveTime::MIN),
+            ),
+            buy: matches!(transaction.operation, Operation::Buy),
+            quantity: transaction.quantity,
+            price: transaction.price,
+            currency,
+            commission: transaction.commission,
+            commission_currency: currency,
+        }
+        .into()
+    }
+}
+
+pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let transactions = read_transactions::<Transaction>(path)?;
+    Ok(transactions.into_iter().map(|entry| entry.into()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_convert() {
+        let statement = "\
+Data transakcji;Godzina;Instrument;Kod ISIN;Rodzaj;Ilość;Cena;Wartość;Prowizja;Waluta
+12.04.2023;09:05:13;PEKAO;PLPEKAO00016;Kupno;15;95,20;1 428,00;5,00;PLN
+02.10.2023;;PEKAO;PLPEKAO00016;Sprzedaż;15;101,00;1 515,00;5,00;PLN
+";
+        let path = env::temp_dir().join("stock_tax_test_pekao.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 2);
+        assert_eq!(
+            activities[0].instrument.isin.as_deref(),
+            Some("PLPEKAO00016")
+        );
+        assert_eq!(
+            activities[0].timestamp,
+            NaiveDate::from_ymd_opt(2023, 4, 12)
+                .unwrap()
+                .and_hms_opt(9, 5, 13)
+                .unwrap()
+        );
+        assert!(matches!(
+            &activities[1].operation,
+            activity::Operation::Sell { quantity, price, .. }
+                if *quantity == dec!(15) && *price.original.get_value() == dec!(101)
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::activity::{Activity, Instrument};
+use crate::currency;
+use crate::mbank::{from_float, read_transactions, Trade};
+use chrono::NaiveDateTime;
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error::Error;
+use std::path::Path;
+
+/// Row of PKO BP Securities transaction history, commission is settled in PLN.
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(
+        rename(deserialize = "Data i czas"),
+        deserialize_with = "from_timestamp"
+    )]
+    timestamp: NaiveDateTime,
+    #[serde(rename(deserialize = "Papier wartościowy"))]
+    symbol: String,
+    #[serde(rename(deserialize = "ISIN"), default)]
+    isin: Option<String>,
+    #[serde(rename(deserialize = "Strona"))]
+    operation: Operation,
+    #[serde(rename(deserialize = "Liczba"), deserialize_with = "from_float")]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Kurs"), deserialize_with = "from_float")]
+    price: Decimal,
+    #[serde(rename(deserialize = "Waluta"), default)]
+    currency: Option<currency::Code>,
+    #[serde(rename(deserialize = "Prowizja"), deserialize_with = "from_float")]
+    commission: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+enum Operation {
+    #[serde(rename(deserialize = "K"), alias = "Kupno")]
+    Buy,
+    #[serde(rename(deserialize = "S"), alias = "Sprzedaż")]
+    Sell,
+}
+
+fn from_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp: &str = Deserialize::deserialize(deserializer)?;
+    let formats = ["%d.%m.%Y %H:%M:%S", "%d.%m.%Y %H:%M"];
+    formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+        .ok_or(de::Error::custom(format!(
+            "Failed to parse \"{}\"",
+            timestamp
+        )))
+}
+
+impl From<Transaction> for Activity {
+    fn from(transaction: Transaction) -> Activity {
+        Trade {
+            instrument: Instrument {
+                symbol: transaction.symbol,
+                isin: transaction.isin.filter(|isin| !isin.is_empty()),
+                ..Default::default()
+            },
+            timestamp: transaction.timestamp,
+            buy: matches!(transaction.operation, Operation::Buy),
+            quantity: transaction.quantity,
+            price: transaction.price,
+            currency: transaction.currency.unwrap_or(currency::Code::PLN),
+            commission: transaction.commission,
+            commission_currency: currency::Code::PLN,
+        }
+        .into()
+    }
+}
+
+pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn Error>> {
+    let transactions = read_transactions::<Transaction>(path)?;
+    Ok(transactions.into_iter().map(|entry| entry.into()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_convert() {
+        let statement = "\u{feff}\
+Data i czas;Papier wartościowy;ISIN;Strona;Liczba;Kurs;Waluta;Wartość;Prowizja
+20.02.2023 11:40;PKOBP;PLPKO0000016;K;100;32,155;PLN;3 215,50;12,54
+21.08.2023 16:59:58;PKOBP;PLPKO0000016;Sprzedaż;40;38,00;PLN;1 520,00;5,93
+";
+        let path = env::temp_dir().join("stock_tax_test_pko.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 2);
+        assert!(matches!(
+            &activities[0].operation,
+            activity::Operation::Buy { quantity, price, commission }
+                if *quantity == dec!(100)
+                    && *price.original.get_value() == dec!(32.155)
+                    && *commission.original.get_value() == dec!(12.54)
+        ));
+        assert!(matches!(
+            &activities[1].operation,
+            activity::Operation::Sell { quantity, .. } if *quantity == dec!(40)
+        ));
+    }
+}
@@ -0,0 +1,518 @@
+use crate::activity::{Activity, Operation};
+use crate::compute::{self, Pit38, Sale, TaxPosition, TaxReturn};
+use crate::currency::{Builder, Pln};
+use crate::identity::Identities;
+use crate::tax::Tax;
+use crate::treaty::Treaties;
+use chrono::NaiveDate;
+use colored::Colorize;
+use derive_more::{Display, Error};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+struct Mismatches {
+    year: i32,
+    values: Vec<String>,
+}
+
+impl Mismatches {
+    fn compare(&mut self, name: &str, derived: Pln, reported: Pln) {
+        self.compare_within(name, derived, reported, 0);
+    }
+
+    /// Compares values allowing a cent of difference for every rounding of a split amount.
+    fn compare_within(&mut self, name: &str, derived: Pln, reported: Pln, splits: usize) {
+        let tolerance = Pln::new(Decimal::new(splits as i64, 2));
+        if (derived - reported).abs() > tolerance {
+            self.values.push(format!(
+                "{year}: {name}: derived {derived} but reported {reported}",
+                year = self.year
+            ));
+        }
+    }
+}
+
+fn split(value: Pln) -> (Pln, Pln) {
+    if value > Pln::new(0) {
+        (value, Pln::default())
+    } else {
+        (Pln::default(), value.abs())
+    }
+}
+
+/// Annual totals and positions reported by compute.
+pub struct Reported<'a> {
+    pub tax_positions: &'a HashMap<&'a str, TaxPosition>,
+    pub tax_return: &'a TaxReturn,
+    pub pit38: &'a Pit38,
+}
+
+/// Re-derives annual totals from raw activities and lot matches and fails if any of them
+/// disagrees with the reported values. Proceeds of short sales opened in the year are
+/// deferred until the positions are closed. Amounts rounded separately per lot, position
+/// or dividend may differ from totals derived at once by a cent for every part.
+pub fn check(
+    year: i32,
+    activities: &[&Activity],
+    sales: &[Sale],
+    deferred: Pln,
+    identities: &Identities,
+    treaties: &Treaties,
+    reported: &Reported,
+) -> Result<(), Error> {
+    let Reported {
+        tax_positions,
+        tax_return,
+        pit38,
+    } = reported;
+    let mut mismatches = Mismatches {
+        year,
+        values: vec![],
+    };
+
+    // Withholding tax adjustments are credited together with the dividend they belong to
+    let key = |activity: &Activity, date: NaiveDate| {
+        (
+            activity.account.to_string(),
+            identities.key(&activity.instrument),
+            date,
+        )
+    };
+    let mut adjustments = HashMap::<(String, String, NaiveDate), Pln>::new();
+    for activity in activities {
+        if let Operation::WithholdingAdjustment {
+            value,
+            dividend_date,
+        } = &activity.operation
+        {
+            let date = dividend_date.unwrap_or(activity.timestamp.date());
+            *adjustments.entry(key(activity, date)).or_default() += value.pln;
+        }
+    }
+
+    let mut revenue = Pln::default();
+    let mut dividend = Pln::default();
+    let mut dividend_withholding_tax = Pln::default();
+    let mut dividend_creditable_tax = Pln::default();
+    let mut dividends = 0;
+    let mut splits = 0;
+    for activity in activities {
+        match &activity.operation {
+            Operation::Sell {
+                quantity, price, ..
+            } => {
+                revenue += price.pln * (*quantity * activity.instrument.multiplier());
+                // Sells exceeding open lots are split into a sale and a short position
+                if sales.iter().any(|sale| {
+                    !sale.short
+                        && sale.symbol == activity.instrument.symbol
+                        && sale.timestamp == activity.timestamp
+                        && sale.quantity != *quantity
+                }) {
+                    splits += 1;
+                }
+            }
+            Operation::Dividend {
+                value,
+                withholding_tax,
+                country,
+            } => {
+                let adjustment = adjustments
+                    .remove(&key(activity, activity.timestamp.date()))
+                    .unwrap_or_default();
+                dividend_creditable_tax += treaties.creditable(
+                    &compute::dividend_country(activity, country),
+                    value.pln,
+                    withholding_tax.pln + adjustment,
+                );
+                dividend += value.pln;
+                dividend_withholding_tax += withholding_tax.pln;
+                dividends += 1;
+            }
+            Operation::PaymentInLieu {
+                value,
+                withholding_tax,
+            } => {
+                // Tax withheld on substitute payments is not creditable
+                adjustments.remove(&key(activity, activity.timestamp.date()));
+                dividend += value.pln;
+                dividend_withholding_tax += withholding_tax.pln;
+                dividends += 1;
+            }
+            Operation::WithholdingAdjustment { value, .. } => dividend_withholding_tax += value.pln,
+            _ => {}
+        }
+    }
+    let (closed, closed_count) = sales
+        .iter()
+        .filter(|sale| sale.short)
+        .fold((Pln::default(), 0), |(acc, count), sale| {
+            (acc + sale.revenue, count + 1)
+        });
+    mismatches.compare_within(
+        "activities stock revenue",
+        revenue - deferred + closed,
+        tax_return.stock_revenue,
+        splits + closed_count,
+    );
+
+    let mut sales_revenue = Pln::default();
+    let mut sales_cost = Pln::default();
+    for sale in sales {
+        let quantity = sale
+            .lots
+            .iter()
+            .fold(Decimal::ZERO, |acc, lot| acc + lot.quantity);
+        if quantity != sale.quantity {
+            mismatches.values.push(format!(
+                "{year}: {symbol} sold on {date}: matched {quantity} of {sold} shares",
+                symbol = sale.symbol,
+                date = sale.timestamp.date(),
+                sold = sale.quantity,
+            ));
+        }
+
+        if sale.short {
+            // Short lots hold sale proceeds, the buy-back cost is known only from the sale
+            let proceeds = sale
+                .lots
+                .iter()
+                .fold(Pln::default(), |acc, lot| acc + lot.cost);
+            mismatches.compare(
+                &format!(
+                    "{} short closed on {} revenue",
+                    sale.symbol,
+                    sale.timestamp.date()
+                ),
+                proceeds,
+                sale.revenue,
+            );
+
+            sales_revenue += sale.revenue;
+            sales_cost += sale.cost;
+            continue;
+        }
+
+        let cost = sale
+            .lots
+            .iter()
+            .fold(sale.commission + sale.adjustment, |acc, lot| {
+                acc + lot.cost + lot.commission
+            });
+        mismatches.compare(
+            &format!("{} sold on {} cost", sale.symbol, sale.timestamp.date()),
+            cost,
+            sale.cost,
+        );
+
+        sales_revenue += sale.revenue;
+        sales_cost += cost;
+    }
+    mismatches.compare(
+        "lot matches stock revenue",
+        sales_revenue,
+        tax_return.stock_revenue,
+    );
+    mismatches.compare("lot matches stock cost", sales_cost, tax_return.stock_cost);
+
+    let (positions_dividend, positions_dividend_withholding_tax) = tax_positions.values().fold(
+        (Pln::default(), Pln::default()),
+        |(dividend, withholding_tax), tax_position| {
+            (
+                dividend + tax_position.dividend,
+                withholding_tax + tax_position.dividend_withholding_tax,
+            )
+        },
+    );
+    mismatches.compare("activities dividend", dividend, positions_dividend);
+    mismatches.compare(
+        "activities dividend withholding tax",
+        dividend_withholding_tax,
+        positions_dividend_withholding_tax,
+    );
+
+    mismatches.compare_within(
+        "activities dividend tax",
+        dividend * Tax::new(19) - dividend_creditable_tax,
+        tax_return.dividend_tax,
+        dividends + tax_positions.len(),
+    );
+    mismatches.compare(
+        "activities dividend excess withholding tax",
+        dividend_withholding_tax - dividend_creditable_tax,
+        tax_return.dividend_excess_tax,
+    );
+
+    // Income and loss are offset only within a position in the annual totals
+    let mut positions = HashMap::<&str, Pln>::new();
+    for sale in sales {
+        *positions.entry(&sale.symbol).or_default() += sale.revenue - sale.cost;
+    }
+    let (positions_income, positions_loss) =
+        positions
+            .values()
+            .fold((Pln::default(), Pln::default()), |(income, loss), value| {
+                let (position_income, position_loss) = split(*value);
+                (income + position_income, loss + position_loss)
+            });
+    mismatches.compare(
+        "lot matches stock income",
+        positions_income,
+        tax_return.stock_income,
+    );
+    mismatches.compare(
+        "lot matches stock loss",
+        positions_loss,
+        tax_return.stock_loss,
+    );
+
+    let (stock_income, stock_loss) = split(sales_revenue - sales_cost);
+    let tax_base = stock_income - pit38.loss_deduction;
+    mismatches.compare("PIT-38 stock revenue", sales_revenue, pit38.stock_revenue);
+    mismatches.compare("PIT-38 stock cost", sales_cost, pit38.stock_cost);
+    let (derivative_revenue, derivative_cost) = sales
+        .iter()
+        .filter(|sale| sale.derivative)
+        .fold((Pln::default(), Pln::default()), |(revenue, cost), sale| {
+            (revenue + sale.revenue, cost + sale.cost)
+        });
+    mismatches.compare(
+        "PIT-38 derivatives revenue",
+        derivative_revenue,
+        pit38.derivative_revenue,
+    );
+    mismatches.compare(
+        "PIT-38 derivatives cost",
+        derivative_cost,
+        pit38.derivative_cost,
+    );
+    mismatches.compare("PIT-38 stock income", stock_income, pit38.stock_income);
+    mismatches.compare("PIT-38 stock loss", stock_loss, pit38.stock_loss);
+    mismatches.compare("PIT-38 tax base", tax_base, pit38.tax_base);
+    mismatches.compare("PIT-38 stock tax", tax_base * Tax::new(19), pit38.stock_tax);
+
+    if mismatches.values.is_empty() {
+        println!("{year}: {prefix} OK", prefix = "RECONCILIATION".green());
+        return Ok(());
+    }
+
+    for mismatch in &mismatches.values {
+        println!("{}", mismatch.red());
+    }
+    Err(Error::new(&format!(
+        "Reconciliation of {} failed with {} mismatches",
+        year,
+        mismatches.values.len()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::{Instrument, Money};
+    use crate::compute::LotMatch;
+    use crate::currency::Usd;
+    use chrono::NaiveDate;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_dividend_tax_mismatch() {
+        let activity = Activity {
+            instrument: Instrument::new("AAPL"),
+            account: String::new(),
+            timestamp: NaiveDate::from_ymd_opt(2022, 5, 12)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            operation: Operation::Dividend {
+                value: Money {
+                    original: Usd::new_box(25),
+                    pln: Pln::new(100),
+                    rate: None,
+                },
+                withholding_tax: Money {
+                    original: Usd::new_box(dec!(3.75)),
+                    pln: Pln::new(15),
+                    rate: None,
+                },
+                country: Some("US".to_string()),
+            },
+        };
+        let mut tax_positions = HashMap::new();
+        tax_positions.insert(
+            "AAPL",
+            TaxPosition {
+                dividend: Pln::new(100),
+                dividend_creditable_tax: Pln::new(15),
+                dividend_withholding_tax: Pln::new(15),
+                ..Default::default()
+            },
+        );
+        let mut tax_return = TaxReturn {
+            dividend_tax: Pln::new(4),
+            ..Default::default()
+        };
+        let pit38 = Pit38::default();
+
+        assert!(check(
+            2022,
+            &[&activity],
+            &[],
+            Pln::default(),
+            &Identities::default(),
+            &Treaties::load(&None).unwrap(),
+            &Reported {
+                tax_positions: &tax_positions,
+                tax_return: &tax_return,
+                pit38: &pit38,
+            },
+        )
+        .is_ok());
+
+        tax_return.dividend_tax = tax_return.dividend_tax * Tax::new(19);
+        assert!(check(
+            2022,
+            &[&activity],
+            &[],
+            Pln::default(),
+            &Identities::default(),
+            &Treaties::load(&None).unwrap(),
+            &Reported {
+                tax_positions: &tax_positions,
+                tax_return: &tax_return,
+                pit38: &pit38,
+            },
+        )
+        .is_err());
+
+        // Tax reported as both credited and excess
+        tax_return.dividend_tax = Pln::new(4);
+        tax_return.dividend_excess_tax = Pln::new(15);
+        assert!(check(
+            2022,
+            &[&activity],
+            &[],
+            Pln::default(),
+            &Identities::default(),
+            &Treaties::load(&None).unwrap(),
+            &Reported {
+                tax_positions: &tax_positions,
+                tax_return: &tax_return,
+                pit38: &pit38,
+            },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_split_sale_rounding() {
+        // 1.5 shares sold with 0.5 matching open lots and the rest opening a short position
+        let activity = Activity {
+            instrument: Instrument::new("CDR"),
+            account: String::new(),
+            timestamp: NaiveDate::from_ymd_opt(2022, 5, 12)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            operation: Operation::Sell {
+                quantity: dec!(1.5),
+                price: Money {
+                    original: Pln::new_box(dec!(3.33)),
+                    pln: Pln::new(dec!(3.33)),
+                    rate: None,
+                },
+                commission: Money {
+                    original: Pln::new_box(0),
+                    pln: Pln::default(),
+                    rate: None,
+                },
+            },
+        };
+        let sale = Sale {
+            symbol: "CDR".to_string(),
+            timestamp: activity.timestamp,
+            quantity: dec!(0.5),
+            revenue: Pln::new(dec!(1.66)),
+            cost: Pln::new(1),
+            commission: Pln::default(),
+            lots: vec![LotMatch {
+                timestamp: activity.timestamp,
+                quantity: dec!(0.5),
+                price: Pln::new(2),
+                cost: Pln::new(1),
+                commission: Pln::default(),
+            }],
+            short: false,
+            derivative: false,
+            adjustment: Pln::default(),
+        };
+        let mut tax_positions = HashMap::new();
+        tax_positions.insert(
+            "CDR",
+            TaxPosition {
+                stock_revenue: Pln::new(dec!(1.66)),
+                stock_cost: Pln::new(1),
+                ..Default::default()
+            },
+        );
+        let mut tax_return = TaxReturn {
+            stock_revenue: Pln::new(dec!(1.66)),
+            stock_cost: Pln::new(1),
+            stock_income: Pln::new(dec!(0.66)),
+            ..Default::default()
+        };
+        let pit38 = Pit38 {
+            stock_revenue: Pln::new(dec!(1.66)),
+            stock_cost: Pln::new(1),
+            stock_income: Pln::new(dec!(0.66)),
+            tax_base: Pln::new(dec!(0.66)),
+            stock_tax: Pln::new(dec!(0.13)),
+            ..Default::default()
+        };
+
+        // Whole sell rounds to 5.00 while its parts round to 1.66 and 3.33
+        assert!(check(
+            2022,
+            &[&activity],
+            std::slice::from_ref(&sale),
+            Pln::new(dec!(3.33)),
+            &Identities::default(),
+            &Treaties::load(&None).unwrap(),
+            &Reported {
+                tax_positions: &tax_positions,
+                tax_return: &tax_return,
+                pit38: &pit38,
+            },
+        )
+        .is_ok());
+
+        tax_return.stock_revenue = Pln::new(dec!(1.64));
+        assert!(check(
+            2022,
+            &[&activity],
+            &[sale],
+            Pln::new(dec!(3.33)),
+            &Identities::default(),
+            &Treaties::load(&None).unwrap(),
+            &Reported {
+                tax_positions: &tax_positions,
+                tax_return: &tax_return,
+                pit38: &pit38,
+            },
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::activity::{Activity, FeeKind, Instrument, Money, Operation};
+use crate::currency;
+use crate::currency::Pln;
+use chrono::NaiveDateTime;
+use colored::Colorize;
+use csv::ReaderBuilder;
+use derive_more::{Display, Error};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error;
+use std::path::Path;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(rename(deserialize = "Date"), deserialize_with = "from_timestamp")]
+    timestamp: NaiveDateTime,
+    #[serde(rename(deserialize = "Ticker"), default)]
+    symbol: String,
+    #[serde(rename(deserialize = "Type"), deserialize_with = "from_type")]
+    kind: Type,
+    #[serde(rename(deserialize = "Quantity"), deserialize_with = "from_amount")]
+    quantity: Option<Decimal>,
+    #[serde(
+        rename(deserialize = "Price per share"),
+        deserialize_with = "from_amount"
+    )]
+    price: Option<Decimal>,
+    #[serde(rename(deserialize = "Total Amount"), deserialize_with = "from_amount")]
+    value: Option<Decimal>,
+    #[serde(rename(deserialize = "Currency"))]
+    currency: currency::Code,
+}
+
+#[derive(Debug, PartialEq)]
+enum Type {
+    Buy,
+    Sell,
+    Dividend,
+    CustodyFee,
+    /// Cash top-ups, withdrawals and other entries not affecting taxes.
+    Other(String),
+}
+
+/// Parses type ignoring order kind, e.g. "BUY - MARKET" or "SELL - LIMIT".
+fn from_type<'de, D>(deserializer: D) -> Result<Type, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let kind: &str = Deserialize::deserialize(deserializer)?;
+    let name = kind.split(" - ").next().unwrap_or_default().trim();
+    Ok(match name {
+        "BUY" => Type::Buy,
+        "SELL" => Type::Sell,
+        "DIVIDEND" => Type::Dividend,
+        "CUSTODY FEE" | "CUSTODY_FEE" => Type::CustodyFee,
+        _ => Type::Other(kind.to_string()),
+    })
+}
+
+/// Parses amount with optional currency code or sign, e.g. "USD 1,234.50", "-$1.50" or "0.125".
+fn from_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let amount: &str = Deserialize::deserialize(deserializer)?;
+    let negative = amount.contains('-');
+    let digits: String = amount
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    if digits.is_empty() {
+        return Ok(None);
+    }
+
+    match Decimal::from_str_exact(&digits) {
+        Ok(value) if negative => Ok(Some(-value)),
+        Ok(value) => Ok(Some(value)),
+        _ => Err(de::Error::custom(format!("Failed to parse \"{}\"", amount))),
+    }
+}
+
+fn from_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp: &str = Deserialize::deserialize(deserializer)?;
+    let formats = [
+        "%Y-%m-%dT%H:%M:%S%.fZ",
+        "%Y-%m-%dT%H:%M:%SZ",
+        "%d/%m/%Y %H:%M:%S",
+    ];
+    formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+        .ok_or(de::Error::custom(format!(
+            "Failed to parse \"{}\"",
+            timestamp
+        )))
+}
+
+impl Transaction {
+    fn money(&self, value: Decimal) -> Money {
+        Money {
+            original: currency::new(&self.currency, value),
+            pln: Pln::default(),
+            rate: None,
+        }
+    }
+
+    fn field(&self, value: Option<Decimal>, name: &str) -> Result<Decimal, Error> {
+        value.ok_or(Error::new(&format!(
+            "Missing {} of {} on {}",
+            name, self.symbol, self.timestamp
+        )))
+    }
+
+    /// Returns dividend as reported, statement gives only the amount net of withholding
+    /// tax without the rate or country it was withheld at.
+    fn dividend(&self) -> Result<Operation, Error> {
+        let net = self.field(self.value, "total amount")?;
+        println!(
+            "{prefix} Dividend of {symbol} on {timestamp} is reported net of withholding tax, recorded without tax withheld",
+            prefix = "WARNING".yellow(),
+            symbol = self.symbol,
+            timestamp = self.timestamp,
+        );
+        Ok(Operation::Dividend {
+            value: self.money(net),
+            withholding_tax: self.money(Decimal::ZERO),
+            country: None,
+        })
+    }
+
+    /// Returns difference between total amount and value of shares, statement has no
+    /// separate commission column.
+    fn commission(&self, quantity: Decimal, price: Decimal) -> Result<Decimal, Error> {
+        let total = self.field(self.value, "total amount")?.abs();
+        let commission = match self.kind {
+            Type::Buy => total - quantity * price,
+            _ => quantity * price - total,
+        };
+        Ok(commission.round_dp(2).max(Decimal::ZERO))
+    }
+}
+
+impl TryInto<Option<Activity>> for Transaction {
+    type Error = Error;
+
+    fn try_into(self) -> Result<Option<Activity>, Self::Error> {
+        let operation = match &self.kind {
+            Type::Buy | Type::Sell => {
+                let quantity = self.field(self.quantity, "quantity")?.abs();
+                let price = self.field(self.price, "price")?;
+                let commission = self.money(self.commission(quantity, price)?);
+                let price = self.money(price);
+                match self.kind {
+                    Type::Buy => Operation::Buy {
+                        quantity,
+                        price,
+                        commission,
+                    },
+                    _ => Operation::Sell {
+                        quantity,
+                        price,
+                        commission,
+                    },
+                }
+            }
+            Type::Dividend => self.dividend()?,
+            Type::CustodyFee => Operation::Fee {
+                value: self.money(self.field(self.value, "total amount")?.abs()),
+                kind: FeeKind::Custody,
+                description: "Custody fee".to_string(),
+            },
+            Type::Other(kind) => {
+                if kind.contains("SPLIT") {
+                    println!(
+                        "{prefix} Stock split of {symbol} on {timestamp} requires corporate actions overrides file",
+                        prefix = "WARNING".yellow(),
+                        symbol = self.symbol,
+                        timestamp = self.timestamp,
+                    );
+                }
+                return Ok(None);
+            }
+        };
+
+        let symbol = match self.symbol.is_empty() {
+            true => self.currency.to_string(),
+            false => self.symbol.clone(),
+        };
+        Ok(Some(Activity {
+            instrument: Instrument::new(&symbol),
+            account: String::new(),
+            timestamp: self.timestamp,
+            operation,
+        }))
+    }
+}
+
+pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(b',')
+        .has_headers(true)
+        .flexible(true)
+        .from_path(path)?;
+
+    let transactions = reader
+        .deserialize::<Transaction>()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let activities = transactions
+        .into_iter()
+        .map(|entry| entry.try_into())
+        .collect::<Result<Vec<Option<Activity>>, _>>()?;
+    Ok(activities.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_convert() {
+        let statement = "\
+Date,Ticker,Type,Quantity,Price per share,Total Amount,Currency,FX Rate
+2022-01-03T09:12:00.123Z,,CASH TOP-UP,,,USD 500,USD,1.00
+2022-01-03T15:30:12.456Z,AAPL,BUY - MARKET,0.12345678,USD 150.00,USD 18.52,USD,1.00
+2022-03-02T10:00:00Z,,CUSTODY FEE,,,USD -0.12,USD,1.00
+2022-05-12T12:00:00.000Z,AAPL,DIVIDEND,,,USD 0.85,USD,1.00
+2022-06-01T16:00:00.000Z,AAPL,SELL - LIMIT,0.12345678,$160.00,$19.75,USD,1.00
+2022-06-02T10:00:00.000Z,SAP,BUY - MARKET,1,\"EUR 1,110.50\",\"EUR 1,111.50\",EUR,1.08
+";
+        let path = env::temp_dir().join("stock_tax_test_revolut.csv");
+        fs::write(&path, statement).unwrap();
+        let activities = convert(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 5);
+        assert!(matches!(
+            &activities[0].operation,
+            Operation::Buy { quantity, price, commission }
+                if *quantity == dec!(0.12345678)
+                    && *price.original.get_value() == dec!(150)
+                    && *commission.original.get_value() == dec!(0)
+        ));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::Fee { value, kind: FeeKind::Custody, .. }
+                if *value.original.get_value() == dec!(0.12)
+        ));
+        assert!(matches!(
+            &activities[2].operation,
+            Operation::Dividend { value, withholding_tax, country }
+                if *value.original.get_value() == dec!(0.85)
+                    && withholding_tax.original.get_value().is_zero()
+                    && country.is_none()
+        ));
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Sell { quantity, .. } if *quantity == dec!(0.12345678)
+        ));
+        assert!(matches!(
+            &activities[4].operation,
+            Operation::Buy { price, commission, .. }
+                if price.original.get_code() == currency::Code::EUR
+                    && *price.original.get_value() == dec!(1110.50)
+                    && *commission.original.get_value() == dec!(1)
+        ));
+    }
+}
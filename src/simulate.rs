@@ -0,0 +1,426 @@
+use crate::activity::{self, Activity, Instrument, Money, Operation};
+use crate::compute::{self, MatchingArgs, Pit38, Sale, Stock, TaxReturn};
+use crate::currency::{self, Currency};
+use crate::loss::{self, Ledger};
+use crate::nbp;
+use chrono::{Datelike, Local, NaiveDate, NaiveTime};
+use clap::Args;
+use colored::Colorize;
+use derive_more::{Display, Error};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::error;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+#[derive(Args)]
+pub struct CommandArgs {
+    path: String,
+    #[command(flatten)]
+    matching: MatchingArgs,
+    #[arg(long)]
+    sells: String,
+    #[arg(long, value_enum, default_value_t = loss::Strategy::Half)]
+    loss_strategy: loss::Strategy,
+    #[arg(long)]
+    losses: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HypotheticalSell {
+    symbol: String,
+    quantity: Decimal,
+    #[serde(deserialize_with = "activity::from_currency")]
+    price: Box<dyn Currency>,
+    #[serde(default, deserialize_with = "activity::from_optional_currency")]
+    commission: Option<Box<dyn Currency>>,
+    date: NaiveDate,
+    #[serde(default)]
+    account: Option<String>,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+fn load_sells(path: &str) -> Result<Vec<HypotheticalSell>, Box<dyn error::Error>> {
+    let file = OpenOptions::new().read(true).open(path)?;
+    let reader = BufReader::new(file);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Converts amount using NBP rate from the business day preceding the sell date,
+/// or the latest published rate when the sell date is in the future.
+fn convert(amount: Box<dyn Currency>, date: &NaiveDate) -> Result<Money, Box<dyn error::Error>> {
+    let today = Local::now().date_naive();
+    let rate_date = if *date > today { today } else { *date };
+    let (pln, rate) = nbp::convert(&amount, &rate_date)?;
+    Ok(Money {
+        original: amount,
+        pln,
+        rate,
+    })
+}
+
+fn into_activity(sell: HypotheticalSell) -> Result<Activity, Box<dyn error::Error>> {
+    let commission = sell
+        .commission
+        .unwrap_or_else(|| currency::new(&sell.price.get_code(), Decimal::ZERO));
+    let price = convert(sell.price, &sell.date)?;
+    let commission = convert(commission, &sell.date)?;
+
+    println!(
+        "{date}: {symbol}: {prefix} quantity: {quantity} price: {price_pln} ({price_org}) commission: {commission_pln} ({commission_org}) rate: {rate}",
+        date = sell.date,
+        symbol = sell.symbol,
+        prefix = "Hypothetical sell".red(),
+        quantity = sell.quantity,
+        price_pln = price.pln,
+        price_org = price.original,
+        commission_pln = commission.pln,
+        commission_org = commission.original,
+        rate = price
+            .rate
+            .as_ref()
+            .map_or("-".to_string(), |rate| rate.to_string()),
+    );
+
+    Ok(Activity {
+        instrument: Instrument::new(&sell.symbol),
+        account: sell.account.unwrap_or_default(),
+        timestamp: sell
+            .date
+            .and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap()),
+        operation: Operation::Sell {
+            quantity: sell.quantity,
+            price,
+            commission,
+        },
+    })
+}
+
+/// Returns the only account holding the instrument, sells of instruments held
+/// in several accounts must name the account.
+fn holding_account(stock: &Stock, instrument: &Instrument) -> Result<String, Error> {
+    let accounts = stock.accounts(instrument);
+    match accounts.as_slice() {
+        [account] => Ok(account.to_string()),
+        [] => Err(Error::new(&format!(
+            "No lots for {} in any account",
+            instrument.symbol
+        ))),
+        _ => Err(Error::new(&format!(
+            "{} is held in several accounts: {}, specify the account",
+            instrument.symbol,
+            accounts.join(", ")
+        ))),
+    }
+}
+
+/// Computes PIT-38 for the year from its sales, deducting losses carried forward and
+/// recording the loss of the year in the ledger.
+fn compute_pit38(ledger: &mut Ledger, sales: &[Sale], year: i32) -> Pit38 {
+    let tax_return = sales
+        .iter()
+        .filter(|sale| sale.timestamp.year() == year)
+        .fold(TaxReturn::default(), |mut acc, sale| {
+            acc.stock_revenue += sale.revenue;
+            acc.stock_cost += sale.cost;
+            if sale.derivative {
+                acc.derivative_revenue += sale.revenue;
+                acc.derivative_cost += sale.cost;
+            }
+            acc
+        });
+    compute::compute_pit38(ledger, year, &tax_return)
+}
+
+/// Carries losses of the years preceding the simulated year forward in the ledger.
+fn carry_forward(ledger: &mut Ledger, sales: &[Sale], year: i32) {
+    let mut years: Vec<_> = sales
+        .iter()
+        .map(|sale| sale.timestamp.year())
+        .filter(|sale_year| *sale_year < year)
+        .collect();
+    years.sort();
+    years.dedup();
+
+    for year in years {
+        compute_pit38(ledger, sales, year);
+    }
+}
+
+fn print_pit38(year: i32, prefix: &str, pit38: &Pit38) {
+    println!(
+        "{year}: {prefix} stock revenue: {stock_revenue} stock cost: {stock_cost} stock income: {stock_income} stock loss: {stock_loss} loss deduction: {loss_deduction} tax base: {tax_base} stock tax: {stock_tax}",
+        prefix = prefix.bright_blue(),
+        stock_revenue = pit38.stock_revenue,
+        stock_cost = pit38.stock_cost,
+        stock_income = pit38.stock_income,
+        stock_loss = pit38.stock_loss,
+        loss_deduction = pit38.loss_deduction,
+        tax_base = pit38.tax_base,
+        stock_tax = pit38.stock_tax,
+    );
+}
+
+/// Replays activities with and without hypothetical sells, returns PIT-38 before and after
+/// together with sales resulting from the hypothetical sells. Activities after the last
+/// hypothetical sell are left out, later sells must not consume the lots it sells.
+fn simulate(
+    matching: &MatchingArgs,
+    mut ledger: Ledger,
+    mut activities: Vec<Activity>,
+    sells: Vec<Activity>,
+    year: i32,
+) -> Result<(Pit38, Pit38, Vec<Sale>), Box<dyn error::Error>> {
+    if let Some(until) = sells.iter().map(|sell| sell.timestamp).max() {
+        activities.retain(|activity| activity.timestamp <= until);
+    }
+
+    let (stock, sales) = compute::replay(matching, &activities)?;
+    carry_forward(&mut ledger, &sales, year);
+    let before = compute_pit38(&mut ledger.clone(), &sales, year);
+
+    let mut hypothetical = vec![];
+    for mut sell in sells {
+        if sell.account.is_empty() {
+            sell.account = holding_account(&stock, &sell.instrument)?;
+        }
+        hypothetical.push((sell.instrument.symbol.to_string(), sell.timestamp));
+        activities.push(sell);
+    }
+    activities.sort_by_key(|activity| activity.timestamp);
+
+    let (_, sales) = compute::replay(matching, &activities)?;
+    let after = compute_pit38(&mut ledger, &sales, year);
+    let sales = sales
+        .into_iter()
+        .filter(|sale| {
+            hypothetical
+                .iter()
+                .any(|(symbol, timestamp)| *symbol == sale.symbol && *timestamp == sale.timestamp)
+        })
+        .collect();
+
+    Ok((before, after, sales))
+}
+
+pub fn command(args: &CommandArgs) -> Result<(), Box<dyn error::Error>> {
+    let activities = compute::load_activities(&args.path)?;
+    let sells = load_sells(&args.sells)?;
+
+    let year = sells
+        .first()
+        .ok_or(Error::new("No hypothetical sells"))?
+        .date
+        .year();
+    if sells.iter().any(|sell| sell.date.year() != year) {
+        return Err(Error::new("All hypothetical sells must be in the same tax year").into());
+    }
+
+    let mut hypothetical = vec![];
+    for sell in sells {
+        hypothetical.push(into_activity(sell)?);
+    }
+
+    let ledger = Ledger::new(args.loss_strategy, compute::load_losses(&args.losses)?);
+    let (before, after, sales) = simulate(&args.matching, ledger, activities, hypothetical, year)?;
+
+    for sale in &sales {
+        println!(
+            "{date}: {symbol}: {prefix} quantity: {quantity} cost: {cost} revenue: {revenue} result: {result}",
+            date = sale.timestamp.date(),
+            symbol = sale.symbol,
+            prefix = "Simulated sale".red(),
+            quantity = sale.quantity,
+            cost = sale.cost,
+            revenue = sale.revenue,
+            result = sale.revenue - sale.cost,
+        );
+        for lot in &sale.lots {
+            println!(
+                "  {date}: Sell block quantity: {quantity} cost: {cost} price: {price} commission: {commission}",
+                date = lot.timestamp.date(),
+                quantity = lot.quantity,
+                cost = lot.cost,
+                price = lot.price,
+                commission = lot.commission,
+            );
+        }
+    }
+
+    print_pit38(year, "PIT-38 BEFORE", &before);
+    print_pit38(year, "PIT-38 AFTER", &after);
+    println!(
+        "{year}: {prefix} stock income: {income} stock loss: {loss} loss deduction: {loss_deduction} stock tax: {tax} (matching: {matching}, loss strategy: {strategy})",
+        prefix = "PIT-38 CHANGE".bright_blue().bold(),
+        income = after.stock_income - before.stock_income,
+        loss = after.stock_loss - before.stock_loss,
+        loss_deduction = after.loss_deduction - before.loss_deduction,
+        tax = after.stock_tax - before.stock_tax,
+        matching = args.matching.method(),
+        strategy = args.loss_strategy,
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::{Builder, Pln};
+    use rust_decimal_macros::dec;
+
+    fn money(value: Decimal) -> Money {
+        Money {
+            original: Pln::new_box(value),
+            pln: Pln::new(value),
+            rate: None,
+        }
+    }
+
+    fn activity(account: &str, month: u32, operation: Operation) -> Activity {
+        Activity {
+            instrument: Instrument::new("CDR"),
+            account: account.to_string(),
+            timestamp: NaiveDate::from_ymd_opt(2022, month, 3)
+                .unwrap()
+                .and_hms_opt(10, 0, 0)
+                .unwrap(),
+            operation,
+        }
+    }
+
+    fn sell(account: &str, month: u32, quantity: Decimal, price: Decimal) -> Activity {
+        activity(
+            account,
+            month,
+            Operation::Sell {
+                quantity,
+                price: money(price),
+                commission: money(Decimal::ZERO),
+            },
+        )
+    }
+
+    fn buy(account: &str, month: u32, quantity: Decimal, price: Decimal) -> Activity {
+        activity(
+            account,
+            month,
+            Operation::Buy {
+                quantity,
+                price: money(price),
+                commission: money(Decimal::ZERO),
+            },
+        )
+    }
+
+    fn ledger() -> Ledger {
+        Ledger::new(loss::Strategy::Half, vec![])
+    }
+
+    fn in_year(year: i32, activity: Activity) -> Activity {
+        Activity {
+            timestamp: activity.timestamp.with_year(year).unwrap(),
+            ..activity
+        }
+    }
+
+    #[test]
+    fn test_converted_activities() {
+        // Converted activities carry the source name as account.
+        let (before, after, sales) = simulate(
+            &MatchingArgs::default(),
+            ledger(),
+            vec![buy("Mbank", 1, dec!(10), dec!(100))],
+            vec![sell("", 6, dec!(4), dec!(150))],
+            2022,
+        )
+        .unwrap();
+        assert_eq!(before.stock_revenue, Pln::default());
+        assert_eq!(after.stock_revenue, Pln::new(dec!(600)));
+        assert_eq!(after.stock_cost, Pln::new(dec!(400)));
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].quantity, dec!(4));
+
+        let history = || {
+            vec![
+                buy("Mbank", 1, dec!(10), dec!(100)),
+                buy("Xtb", 2, dec!(1), dec!(120)),
+            ]
+        };
+        assert!(simulate(
+            &MatchingArgs::default(),
+            ledger(),
+            history(),
+            vec![sell("", 6, dec!(1), dec!(150))],
+            2022,
+        )
+        .is_err());
+
+        let (_, after, _) = simulate(
+            &MatchingArgs::default(),
+            ledger(),
+            history(),
+            vec![sell("Xtb", 6, dec!(1), dec!(150))],
+            2022,
+        )
+        .unwrap();
+        assert_eq!(after.stock_cost, Pln::new(dec!(120)));
+
+        // Later sell of all shares is not replayed after the hypothetical one
+        let (before, after, _) = simulate(
+            &MatchingArgs::default(),
+            ledger(),
+            vec![
+                buy("Mbank", 1, dec!(10), dec!(100)),
+                sell("Mbank", 9, dec!(10), dec!(200)),
+            ],
+            vec![sell("", 6, dec!(4), dec!(150))],
+            2022,
+        )
+        .unwrap();
+        assert_eq!(before.stock_revenue, Pln::default());
+        assert_eq!(after.stock_revenue, Pln::new(dec!(600)));
+    }
+
+    #[test]
+    fn test_loss_carry_forward() {
+        let activities = vec![
+            in_year(2021, buy("Mbank", 1, dec!(10), dec!(100))),
+            in_year(2021, sell("Mbank", 2, dec!(10), dec!(80))),
+            buy("Mbank", 3, dec!(10), dec!(100)),
+            sell("Mbank", 4, dec!(2), dec!(110)),
+        ];
+
+        let (before, after, sales) = simulate(
+            &MatchingArgs::default(),
+            ledger(),
+            activities,
+            vec![sell("", 6, dec!(8), dec!(150))],
+            2022,
+        )
+        .unwrap();
+        assert_eq!(before.stock_income, Pln::new(20));
+        assert_eq!(before.loss_deduction, Pln::new(20));
+        assert_eq!(before.stock_tax, Pln::default());
+
+        // Half of the 2021 loss is deductible in 2022, with and without the hypothetical sell
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].cost, Pln::new(800));
+        assert_eq!(after.stock_income, Pln::new(420));
+        assert_eq!(after.loss_deduction, Pln::new(100));
+        assert_eq!(after.tax_base, Pln::new(320));
+        assert_eq!(after.stock_tax, Pln::new(dec!(60.80)));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::activity::{Activity, Instrument, Money, Operation};
+use crate::currency;
+use crate::currency::Pln;
+use chrono::NaiveDateTime;
+use colored::Colorize;
+use csv::{ReaderBuilder, StringRecord};
+use derive_more::{Display, Error};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::error;
+use std::path::Path;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(rename(deserialize = "Action"))]
+    action: String,
+    #[serde(rename(deserialize = "Time"), deserialize_with = "from_timestamp")]
+    timestamp: NaiveDateTime,
+    #[serde(rename(deserialize = "ISIN"), default)]
+    isin: String,
+    #[serde(rename(deserialize = "Ticker"), default)]
+    symbol: String,
+    #[serde(
+        rename(deserialize = "No. of shares"),
+        deserialize_with = "from_decimal",
+        default
+    )]
+    quantity: Decimal,
+    #[serde(
+        rename(deserialize = "Price / share"),
+        deserialize_with = "from_decimal",
+        default
+    )]
+    price: Decimal,
+    #[serde(
+        rename(deserialize = "Currency (Price / share)"),
+        deserialize_with = "from_optional_currency",
+        default
+    )]
+    price_currency: Option<currency::Code>,
+    #[serde(rename(deserialize = "Total"), deserialize_with = "from_decimal")]
+    total: Decimal,
+    #[serde(
+        rename(deserialize = "Currency (Total)"),
+        deserialize_with = "from_optional_currency",
+        default
+    )]
+    total_currency: Option<currency::Code>,
+    #[serde(
+        rename(deserialize = "Withholding tax"),
+        deserialize_with = "from_decimal",
+        default
+    )]
+    withholding_tax: Decimal,
+    #[serde(
+        rename(deserialize = "Currency (Withholding tax)"),
+        deserialize_with = "from_optional_currency",
+        default
+    )]
+    withholding_tax_currency: Option<currency::Code>,
+    #[serde(
+        rename(deserialize = "Currency conversion fee"),
+        deserialize_with = "from_decimal",
+        default
+    )]
+    conversion_fee: Decimal,
+    #[serde(
+        rename(deserialize = "Currency (Currency conversion fee)"),
+        deserialize_with = "from_optional_currency",
+        default
+    )]
+    conversion_fee_currency: Option<currency::Code>,
+}
+
+fn from_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: &str = Deserialize::deserialize(deserializer)?;
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+
+    match Decimal::from_str_exact(value).or_else(|_| Decimal::from_scientific(value)) {
+        Ok(value) => Ok(value),
+        _ => Err(de::Error::custom(format!("Failed to parse \"{}\"", value))),
+    }
+}
+
+fn from_optional_currency<'de, D>(deserializer: D) -> Result<Option<currency::Code>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let code: &str = Deserialize::deserialize(deserializer)?;
+    match code.trim() {
+        "" => Ok(None),
+        code => serde_json::from_value(serde_json::Value::String(code.to_string()))
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("Unsupported currency \"{}\"", code))),
+    }
+}
+
+fn from_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp: &str = Deserialize::deserialize(deserializer)?;
+    let formats = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%d %H:%M:%S"];
+    formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+        .ok_or(de::Error::custom(format!(
+            "Failed to parse \"{}\"",
+            timestamp
+        )))
+}
+
+/// Strips account currency from columns of older exports, e.g. "Total (EUR)" becomes "Total"
+/// and the currency is returned for the missing "Currency (Total)" column.
+fn normalize_header(header: &StringRecord) -> (StringRecord, HashMap<String, currency::Code>) {
+    let mut currencies = HashMap::new();
+    let header = header
+        .iter()
+        .map(|column| {
+            let Some((name, code)) = column
+                .strip_suffix(')')
+                .and_then(|column| column.rsplit_once(" ("))
+            else {
+                return column.to_string();
+            };
+            match serde_json::from_value(serde_json::Value::String(code.to_string())) {
+                Ok(code) => {
+                    currencies.insert(name.to_string(), code);
+                    name.to_string()
+                }
+                Err(_) => column.to_string(),
+            }
+        })
+        .collect();
+    (header, currencies)
+}
+
+impl Transaction {
+    fn money(code: Option<currency::Code>, value: Decimal, name: &str) -> Result<Money, Error> {
+        let code = code.ok_or(Error::new(&format!("Unknown currency of {}", name)))?;
+        Ok(Money {
+            original: currency::new(&code, value.abs()),
+            pln: Pln::default(),
+            rate: None,
+        })
+    }
+
+    fn instrument(&self) -> Instrument {
+        Instrument {
+            symbol: self.symbol.clone(),
+            isin: match self.isin.is_empty() {
+                true => None,
+                false => Some(self.isin.clone()),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Currency conversion fee is the only charge of a trade and becomes its commission.
+    fn into_activity(self) -> Result<Option<Activity>, Error> {
+        let action = self.action.to_lowercase();
+        let operation = if action.ends_with(" buy") || action.ends_with(" sell") {
+            let price = Self::money(self.price_currency, self.price, "price")?;
+            let commission = match self.conversion_fee.is_zero() {
+                true => Self::money(self.price_currency, Decimal::ZERO, "price")?,
+                false => Self::money(
+                    self.conversion_fee_currency,
+                    self.conversion_fee,
+                    "currency conversion fee",
+                )?,
+            };
+            let quantity = self.quantity.abs();
+            match action.ends_with(" buy") {
+                true => Operation::Buy {
+                    quantity,
+                    price,
+                    commission,
+                },
+                false => Operation::Sell {
+                    quantity,
+                    price,
+                    commission,
+                },
+            }
+        } else if action.starts_with("dividend") {
+            // Price per share is the gross dividend in currency of the security
+            let value = (self.quantity * self.price).round_dp(2);
+            let tax_currency = self.withholding_tax_currency.or(self.price_currency);
+            Operation::Dividend {
+                value: Self::money(self.price_currency, value, "dividend")?,
+                withholding_tax: Self::money(tax_currency, self.withholding_tax, "tax")?,
+                country: None,
+            }
+        } else if action == "interest on cash" {
+            Operation::Interest {
+                value: Self::money(self.total_currency, self.total, "interest")?,
+                withholding_tax: Self::money(self.total_currency, Decimal::ZERO, "interest")?,
+            }
+        } else {
+            // Cash movements have no ticker, skipped actions of a security change its position
+            if !self.symbol.is_empty() {
+                println!(
+                    "{prefix} {action} of {symbol} on {timestamp} skipped, position changes require corporate actions overrides file",
+                    prefix = "WARNING".yellow(),
+                    action = self.action,
+                    symbol = self.symbol,
+                    timestamp = self.timestamp,
+                );
+            }
+            return Ok(None);
+        };
+
+        let instrument = match self.symbol.is_empty() {
+            true => Instrument::new(
+                &self
+                    .total_currency
+                    .map(|code| code.to_string())
+                    .unwrap_or_default(),
+            ),
+            false => self.instrument(),
+        };
+        Ok(Some(Activity {
+            instrument,
+            account: String::new(),
+            timestamp: self.timestamp,
+            operation,
+        }))
+    }
+}
+
+pub fn convert(path: &Path) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(b',')
+        .has_headers(true)
+        .flexible(true)
+        .from_path(path)?;
+    let (header, currencies) = normalize_header(reader.headers()?);
+
+    let mut activities = vec![];
+    for record in reader.records() {
+        let mut transaction: Transaction = record?.deserialize(Some(&header))?;
+        transaction.total_currency = transaction
+            .total_currency
+            .or(currencies.get("Total").copied());
+        transaction.conversion_fee_currency = transaction
+            .conversion_fee_currency
+            .or(currencies.get("Currency conversion fee").copied());
+        activities.extend(transaction.into_activity()?);
+    }
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn fixture(name: &str) -> Vec<Activity> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name);
+        convert(&path).unwrap()
+    }
+
+    #[test]
+    fn test_convert() {
+        let activities = fixture("trading212.csv");
+
+        assert_eq!(activities.len(), 5);
+        let buy = &activities[0];
+        assert_eq!(buy.instrument.isin.as_deref(), Some("US0378331005"));
+        let Operation::Buy {
+            quantity,
+            price,
+            commission,
+        } = &buy.operation
+        else {
+            panic!("Expected buy");
+        };
+        assert_eq!(*quantity, dec!(1.5));
+        assert_eq!(price.original.get_code(), currency::Code::USD);
+        assert_eq!(commission.original.get_code(), currency::Code::PLN);
+        assert_eq!(*commission.original.get_value(), dec!(1.36));
+
+        let Operation::Dividend {
+            value,
+            withholding_tax,
+            ..
+        } = &activities[1].operation
+        else {
+            panic!("Expected dividend");
+        };
+        assert_eq!(*value.original.get_value(), dec!(0.36));
+        assert_eq!(*withholding_tax.original.get_value(), dec!(0.05));
+        assert_eq!(withholding_tax.original.get_code(), currency::Code::USD);
+
+        assert!(matches!(
+            &activities[2].operation,
+            Operation::Sell { quantity, .. } if *quantity == dec!(1.5)
+        ));
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Buy { commission, .. } if *commission.original.get_value() == dec!(0)
+        ));
+        assert!(matches!(
+            &activities[4].operation,
+            Operation::Interest { value, .. } if *value.original.get_value() == dec!(0.42)
+        ));
+    }
+
+    #[test]
+    fn test_account_currency_in_header() {
+        let activities = fixture("trading212_eur.csv");
+
+        assert_eq!(activities.len(), 2);
+        assert!(matches!(
+            &activities[0].operation,
+            Operation::Buy { commission, .. }
+                if commission.original.get_code() == currency::Code::EUR
+                    && *commission.original.get_value() == dec!(0.15)
+        ));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::Dividend { withholding_tax, .. }
+                if withholding_tax.original.get_code() == currency::Code::USD
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+use crate::currency::{Builder, Pln};
+use crate::tax::Tax;
+use colored::Colorize;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+
+/// Maximum withholding tax rates creditable in Poland under double taxation treaties.
+const RATES: [(&str, i64); 12] = [
+    ("US", 15),
+    ("GB", 0),
+    ("IE", 15),
+    ("DE", 15),
+    ("FR", 15),
+    ("NL", 15),
+    ("CA", 15),
+    ("CH", 15),
+    ("DK", 15),
+    ("SE", 15),
+    ("NO", 15),
+    ("PL", 19),
+];
+
+pub struct Treaties {
+    rates: HashMap<String, i64>,
+    /// Countries already reported as missing a treaty rate.
+    unknown: RefCell<HashSet<String>>,
+}
+
+impl Treaties {
+    pub fn load(path: &Option<String>) -> Result<Treaties, Box<dyn error::Error>> {
+        let mut rates: HashMap<String, i64> = RATES
+            .iter()
+            .map(|(country, rate)| (country.to_string(), *rate))
+            .collect();
+
+        if let Some(path) = path {
+            let file = OpenOptions::new().read(true).open(path)?;
+            let reader = BufReader::new(file);
+            let overrides: HashMap<String, i64> = serde_json::from_reader(reader)?;
+            rates.extend(overrides);
+        }
+
+        Ok(Treaties {
+            rates,
+            unknown: RefCell::new(HashSet::new()),
+        })
+    }
+
+    /// Returns treaty rate for given country, falls back to Polish rate for unknown countries.
+    pub fn rate(&self, country: &Option<String>) -> Tax {
+        match country.as_ref().and_then(|country| self.rates.get(country)) {
+            Some(rate) => Tax::new(*rate),
+            None => {
+                let country = country.as_deref().unwrap_or("<unknown>");
+                if self.unknown.borrow_mut().insert(country.to_string()) {
+                    println!(
+                        "{prefix} No treaty rate for country {country}, crediting withholding tax up to 19%",
+                        prefix = "WARNING".yellow(),
+                    );
+                }
+                Tax::new(19)
+            }
+        }
+    }
+
+    /// Returns withholding tax creditable against Polish tax on the dividend, limited by
+    /// the treaty rate and by the Polish tax itself.
+    pub fn creditable(&self, country: &Option<String>, value: Pln, withheld: Pln) -> Pln {
+        if withheld <= Pln::new(0) {
+            return Pln::default();
+        }
+
+        [value * self.rate(country), value * Tax::new(19)]
+            .into_iter()
+            .fold(withheld, |acc, limit| if limit < acc { limit } else { acc })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn treaties(overrides: &[(&str, i64)]) -> Treaties {
+        let mut treaties = Treaties::load(&None).unwrap();
+        treaties.rates.extend(
+            overrides
+                .iter()
+                .map(|(country, rate)| (country.to_string(), *rate)),
+        );
+        treaties
+    }
+
+    #[test]
+    fn test_treaty_rate_cap() {
+        let treaties = treaties(&[]);
+        let us = Some("US".to_string());
+        assert_eq!(
+            treaties.creditable(&us, Pln::new(dec!(100)), Pln::new(dec!(30))),
+            Pln::new(dec!(15))
+        );
+        assert_eq!(
+            treaties.creditable(&us, Pln::new(dec!(100)), Pln::new(dec!(10))),
+            Pln::new(dec!(10))
+        );
+        assert_eq!(
+            treaties.creditable(
+                &Some("GB".to_string()),
+                Pln::new(dec!(100)),
+                Pln::new(dec!(5))
+            ),
+            Pln::default()
+        );
+    }
+
+    #[test]
+    fn test_local_tax_floor() {
+        let treaties = treaties(&[("CH", 35)]);
+        assert_eq!(
+            treaties.creditable(
+                &Some("CH".to_string()),
+                Pln::new(dec!(100)),
+                Pln::new(dec!(35))
+            ),
+            Pln::new(dec!(19))
+        );
+
+        let unknown = Some("ZZ".to_string());
+        assert_eq!(
+            treaties.creditable(&unknown, Pln::new(dec!(100)), Pln::new(dec!(25))),
+            Pln::new(dec!(19))
+        );
+        treaties.creditable(&unknown, Pln::new(dec!(100)), Pln::new(dec!(25)));
+        assert_eq!(treaties.unknown.borrow().len(), 1);
+
+        assert_eq!(
+            treaties.creditable(&unknown, Pln::new(dec!(100)), Pln::new(dec!(-3))),
+            Pln::default()
+        );
+    }
+}
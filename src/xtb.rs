@@ -0,0 +1,550 @@
+use crate::activity::{Activity, FeeKind, Instrument, Money, Operation};
+use crate::currency;
+use crate::currency::Pln;
+use crate::mbank::from_float;
+use calamine::{open_workbook_auto, Data, DataType, Reader};
+use chrono::{NaiveDate, NaiveDateTime};
+use colored::Colorize;
+use csv::{ReaderBuilder, StringRecord};
+use derive_more::{Display, Error};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer};
+use std::error;
+use std::path::Path;
+
+#[derive(Display, Error, Debug)]
+pub struct Error {
+    reason: String,
+}
+
+impl Error {
+    fn new(reason: &str) -> Error {
+        Error {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClosedPosition {
+    #[serde(rename(deserialize = "Symbol"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Type"))]
+    side: String,
+    #[serde(rename(deserialize = "Volume"), deserialize_with = "from_float")]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Open time"), deserialize_with = "from_timestamp")]
+    open_time: NaiveDateTime,
+    #[serde(
+        rename(deserialize = "Close time"),
+        deserialize_with = "from_timestamp"
+    )]
+    close_time: NaiveDateTime,
+    #[serde(
+        rename(deserialize = "Purchase value"),
+        deserialize_with = "from_float"
+    )]
+    purchase_value: Decimal,
+    #[serde(rename(deserialize = "Sale value"), deserialize_with = "from_float")]
+    sale_value: Decimal,
+    #[serde(
+        rename(deserialize = "Commission"),
+        deserialize_with = "from_float",
+        default
+    )]
+    commission: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenPosition {
+    #[serde(rename(deserialize = "Symbol"))]
+    symbol: String,
+    #[serde(rename(deserialize = "Type"))]
+    side: String,
+    #[serde(rename(deserialize = "Volume"), deserialize_with = "from_float")]
+    quantity: Decimal,
+    #[serde(rename(deserialize = "Open time"), deserialize_with = "from_timestamp")]
+    open_time: NaiveDateTime,
+    #[serde(
+        rename(deserialize = "Purchase value"),
+        deserialize_with = "from_float"
+    )]
+    purchase_value: Decimal,
+    #[serde(
+        rename(deserialize = "Commission"),
+        deserialize_with = "from_float",
+        default
+    )]
+    commission: Decimal,
+}
+
+#[derive(Debug, Deserialize)]
+struct CashOperation {
+    #[serde(rename(deserialize = "Type"))]
+    kind: String,
+    #[serde(rename(deserialize = "Time"), deserialize_with = "from_timestamp")]
+    timestamp: NaiveDateTime,
+    #[serde(rename(deserialize = "Comment"), default)]
+    comment: String,
+    #[serde(rename(deserialize = "Symbol"), default)]
+    symbol: String,
+    #[serde(rename(deserialize = "Amount"), deserialize_with = "from_float")]
+    value: Decimal,
+}
+
+/// Account of the statement, XTB keeps a separate account for every currency.
+struct Account {
+    id: String,
+    currency: currency::Code,
+}
+
+fn from_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp: &str = Deserialize::deserialize(deserializer)?;
+    let formats = ["%d.%m.%Y %H:%M:%S", "%d.%m.%Y %H:%M", "%Y-%m-%d %H:%M:%S"];
+    formats
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp.trim(), format).ok())
+        .ok_or(de::Error::custom(format!(
+            "Failed to parse \"{}\"",
+            timestamp
+        )))
+}
+
+/// Splits market suffix from symbol, e.g. "AAPL.US" or "CDR.PL".
+fn parse_symbol(symbol: &str) -> Instrument {
+    match symbol.rsplit_once('.') {
+        Some((symbol, market)) => Instrument {
+            symbol: symbol.to_string(),
+            exchange: Some(market.to_string()),
+            ..Default::default()
+        },
+        None => Instrument::new(symbol),
+    }
+}
+
+/// Country of dividend source from market suffix, withholding follows the listing.
+fn parse_country(symbol: &str) -> Option<String> {
+    let (_, market) = symbol.rsplit_once('.')?;
+    match market {
+        "UK" => Some("GB".to_string()),
+        market if market.len() == 2 => Some(market.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_currency(code: &str) -> Result<currency::Code, Error> {
+    serde_json::from_value(serde_json::Value::String(code.trim().to_string())).or(Err(Error::new(
+        &format!("Unsupported account currency {}", code),
+    )))
+}
+
+fn to_text(cell: &Data) -> String {
+    match cell {
+        Data::Float(value) => Decimal::from_f64(*value)
+            .map(|value| value.round_dp(8).normalize().to_string())
+            .unwrap_or_default(),
+        Data::DateTime(_) => cell
+            .as_datetime()
+            .map(|timestamp| timestamp.format("%d.%m.%Y %H:%M:%S").to_string())
+            .unwrap_or_default(),
+        Data::Empty => String::new(),
+        cell => cell.to_string(),
+    }
+}
+
+/// Reads every worksheet of xlsx export or the single table of CSV export as rows of text.
+fn read_tables(path: &Path) -> Result<Vec<Vec<StringRecord>>, Box<dyn error::Error>> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    if !matches!(extension, Some("xlsx") | Some("xls")) {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b';')
+            .has_headers(false)
+            .flexible(true)
+            .from_path(path)?;
+        let rows = reader.records().collect::<Result<Vec<_>, _>>()?;
+        return Ok(vec![rows]);
+    }
+
+    let mut workbook = open_workbook_auto(path)?;
+    let mut tables = vec![];
+    for (_, range) in workbook.worksheets() {
+        let rows = range
+            .rows()
+            .map(|row| row.iter().map(to_text).collect::<StringRecord>())
+            .collect();
+        tables.push(rows);
+    }
+    Ok(tables)
+}
+
+/// Returns value below the label in the account summary above the tables.
+fn find_label(tables: &[Vec<StringRecord>], label: &str) -> Option<String> {
+    tables.iter().find_map(|rows| {
+        rows.windows(2).find_map(|rows| {
+            let index = rows[0].iter().position(|cell| cell.trim() == label)?;
+            let value = rows[1].get(index)?.trim();
+            match value.is_empty() {
+                true => None,
+                false => Some(value.to_string()),
+            }
+        })
+    })
+}
+
+fn find_header(rows: &[StringRecord], columns: &[&str]) -> Option<usize> {
+    rows.iter().position(|row| {
+        columns
+            .iter()
+            .all(|column| row.iter().any(|cell| cell.trim() == *column))
+    })
+}
+
+/// Deserializes rows following the first header with all columns, ends at totals.
+fn extract<T>(rows: &[StringRecord], columns: &[&str]) -> Result<Vec<T>, Box<dyn error::Error>>
+where
+    T: de::DeserializeOwned,
+{
+    let Some(index) = find_header(rows, columns) else {
+        return Ok(vec![]);
+    };
+
+    let header: StringRecord = rows[index].iter().map(|cell| cell.trim()).collect();
+    let mut values = vec![];
+    for row in &rows[index + 1..] {
+        let first = row.get(0).unwrap_or_default().trim();
+        if first == "Total" || row.iter().all(|cell| cell.trim().is_empty()) {
+            break;
+        }
+        values.push(row.deserialize(Some(&header))?);
+    }
+    Ok(values)
+}
+
+fn money(account: &Account, value: Decimal) -> Money {
+    Money {
+        original: currency::new(&account.currency, value),
+        pln: Pln::default(),
+        rate: None,
+    }
+}
+
+/// Creates trade with price per share in account currency, XTB converts foreign shares
+/// at its own rate so the account currency amounts are the actual cost and revenue.
+fn trade(
+    account: &Account,
+    symbol: &str,
+    timestamp: NaiveDateTime,
+    buy: bool,
+    quantity: Decimal,
+    value: Decimal,
+    commission: Decimal,
+) -> Result<Activity, Error> {
+    if quantity.is_zero() {
+        return Err(Error::new(&format!(
+            "{timestamp}: {symbol}: Position without volume"
+        )));
+    }
+
+    let price = money(account, (value.abs() / quantity).round_dp(4));
+    let commission = money(account, commission.abs().round_dp(2));
+    Ok(Activity {
+        instrument: parse_symbol(symbol),
+        account: account.id.clone(),
+        timestamp,
+        operation: match buy {
+            true => Operation::Buy {
+                quantity,
+                price,
+                commission,
+            },
+            false => Operation::Sell {
+                quantity,
+                price,
+                commission,
+            },
+        },
+    })
+}
+
+impl ClosedPosition {
+    /// Short positions are opened with a sale and closed with a purchase.
+    fn into_activities(self, account: &Account) -> Result<[Activity; 2], Error> {
+        let long = self.side.eq_ignore_ascii_case("BUY");
+        let open = trade(
+            account,
+            &self.symbol,
+            self.open_time,
+            long,
+            self.quantity,
+            self.purchase_value,
+            Decimal::ZERO,
+        )?;
+        let close = trade(
+            account,
+            &self.symbol,
+            self.close_time,
+            !long,
+            self.quantity,
+            self.sale_value,
+            self.commission,
+        )?;
+        Ok([open, close])
+    }
+}
+
+impl OpenPosition {
+    fn into_activity(self, account: &Account) -> Result<Activity, Error> {
+        trade(
+            account,
+            &self.symbol,
+            self.open_time,
+            self.side.eq_ignore_ascii_case("BUY"),
+            self.quantity,
+            self.purchase_value,
+            self.commission,
+        )
+    }
+}
+
+/// Converts cash operations, withholding taxes are matched with dividends of the same symbol
+/// and day, the remaining ones are adjustments of earlier dividends.
+fn convert_cash_operations(operations: Vec<CashOperation>, account: &Account) -> Vec<Activity> {
+    let (taxes, operations): (Vec<_>, Vec<_>) = operations
+        .into_iter()
+        .partition(|operation| operation.kind.to_lowercase().ends_with(" tax"));
+    let mut taxes: Vec<_> = taxes.into_iter().map(Some).collect();
+    let mut take_tax = |kind: &str, symbol: &str, date: NaiveDate| {
+        taxes
+            .iter_mut()
+            .find(|tax| {
+                tax.as_ref().is_some_and(|tax| {
+                    tax.kind.to_lowercase().starts_with(kind)
+                        && tax.symbol == symbol
+                        && tax.timestamp.date() == date
+                })
+            })
+            .and_then(|tax| tax.take())
+            .map_or(Decimal::ZERO, |tax| tax.value.abs())
+    };
+
+    let mut activities = vec![];
+    for entry in operations {
+        let kind = entry.kind.to_lowercase();
+        let date = entry.timestamp.date();
+        let cash = Instrument::new(&account.currency.to_string());
+
+        let (instrument, operation) = match kind.as_str() {
+            // XTB exports name dividends "DIVIDENT"
+            "divident" | "dividend" => {
+                let tax = take_tax("withholding", &entry.symbol, date);
+                let operation = Operation::Dividend {
+                    value: money(account, entry.value),
+                    withholding_tax: money(account, tax),
+                    country: parse_country(&entry.symbol),
+                };
+                (parse_symbol(&entry.symbol), operation)
+            }
+            "free-funds interest" => {
+                let tax = take_tax("free-funds interest", &entry.symbol, date);
+                let operation = Operation::Interest {
+                    value: money(account, entry.value),
+                    withholding_tax: money(account, tax),
+                };
+                (cash, operation)
+            }
+            kind if kind.contains("fee") => {
+                let operation = Operation::Fee {
+                    value: money(account, -entry.value),
+                    kind: FeeKind::Other,
+                    description: format!("{} {}", entry.kind, entry.comment)
+                        .trim()
+                        .to_string(),
+                };
+                (cash, operation)
+            }
+            _ => continue,
+        };
+
+        activities.push(Activity {
+            instrument,
+            account: account.id.clone(),
+            timestamp: entry.timestamp,
+            operation,
+        });
+    }
+
+    for tax in taxes.into_iter().flatten() {
+        if !tax.kind.to_lowercase().starts_with("withholding") {
+            println!(
+                "{prefix} Unmatched {kind} on {timestamp}: {comment}",
+                prefix = "WARNING".yellow(),
+                kind = tax.kind,
+                timestamp = tax.timestamp,
+                comment = tax.comment,
+            );
+            continue;
+        }
+        activities.push(Activity {
+            instrument: parse_symbol(&tax.symbol),
+            account: account.id.clone(),
+            timestamp: tax.timestamp,
+            operation: Operation::WithholdingAdjustment {
+                value: money(account, -tax.value),
+                dividend_date: None,
+            },
+        });
+    }
+    activities
+}
+
+/// Converts closed positions, open positions and cash operations of xlsx or CSV export,
+/// currency of CSV exports which do not include account summary has to be given.
+pub fn convert(
+    path: &Path,
+    currency: Option<&str>,
+) -> Result<Vec<Activity>, Box<dyn error::Error>> {
+    let tables = read_tables(path)?;
+    let currency = match currency.map(str::to_string) {
+        Some(code) => code,
+        None => find_label(&tables, "Currency").ok_or(Error::new(
+            "Account currency not found in XTB statement, use --currency",
+        ))?,
+    };
+    let account = Account {
+        id: find_label(&tables, "Account").unwrap_or_default(),
+        currency: parse_currency(&currency)?,
+    };
+
+    let closed = ["Symbol", "Type", "Volume", "Open time", "Close time"];
+    let open = ["Symbol", "Type", "Volume", "Open time", "Purchase value"];
+    let cash = ["Type", "Time", "Amount"];
+
+    let mut activities = vec![];
+    for rows in &tables {
+        if find_header(rows, &closed).is_some() {
+            for position in extract::<ClosedPosition>(rows, &closed)? {
+                activities.extend(position.into_activities(&account)?);
+            }
+        } else if find_header(rows, &open).is_some() {
+            for position in extract::<OpenPosition>(rows, &open)? {
+                activities.push(position.into_activity(&account)?);
+            }
+        } else {
+            let operations = extract::<CashOperation>(rows, &cash)?;
+            activities.extend(convert_cash_operations(operations, &account));
+        }
+    }
+
+    activities.sort_by_key(|activity| activity.timestamp);
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_workbook() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/xtb.xlsx");
+        let activities = convert(&path, None).unwrap();
+
+        assert_eq!(activities.len(), 6);
+        assert!(activities
+            .iter()
+            .all(|activity| activity.account == "51234567"));
+
+        let buy = &activities[0];
+        assert_eq!(buy.instrument.symbol, "AAPL");
+        assert_eq!(buy.instrument.exchange.as_deref(), Some("US"));
+        let Operation::Buy {
+            quantity, price, ..
+        } = &buy.operation
+        else {
+            panic!("Expected buy");
+        };
+        assert_eq!(*quantity, dec!(2.5));
+        assert_eq!(price.original.get_code(), currency::Code::USD);
+        assert_eq!(*price.original.get_value(), dec!(150));
+
+        let Operation::Dividend {
+            value,
+            withholding_tax,
+            country,
+        } = &activities[2].operation
+        else {
+            panic!("Expected dividend");
+        };
+        assert_eq!(*value.original.get_value(), dec!(0.58));
+        assert_eq!(*withholding_tax.original.get_value(), dec!(0.09));
+        assert_eq!(country.as_deref(), Some("US"));
+
+        assert!(matches!(
+            &activities[3].operation,
+            Operation::Sell { quantity, price, .. }
+                if *quantity == dec!(2.5) && *price.original.get_value() == dec!(160)
+        ));
+        assert!(matches!(
+            &activities[4].operation,
+            Operation::Sell { commission, .. } if *commission.original.get_value() == dec!(0.55)
+        ));
+        assert!(matches!(
+            &activities[5].operation,
+            Operation::Interest { withholding_tax, .. }
+                if *withholding_tax.original.get_value() == dec!(0.23)
+        ));
+    }
+
+    #[test]
+    fn test_closed_position_without_volume() {
+        let statement = "\
+Position;Symbol;Type;Volume;Open time;Close time;Purchase value;Sale value;Commission
+1001;AAPL.US;BUY;0;03.01.2022 15:30:00;03.01.2022 15:31:00;0;0;0
+";
+        let path = env::temp_dir().join("stock_tax_test_xtb_closed_without_volume.csv");
+        fs::write(&path, statement).unwrap();
+        let result = convert(&path, Some("USD"));
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cash_operations_csv() {
+        let statement = "\
+ID;Type;Time;Comment;Symbol;Amount
+3001;DIVIDENT;12.05.2022 12:00:00;CDR.PL PLN 1.0000/ SHR;CDR.PL;10
+3002;Withholding Tax;12.05.2022 12:00:00;CDR.PL PLN WHT 19%;CDR.PL;-1,9
+3003;Withholding Tax;10.02.2023 12:00:00;CDR.PL PLN WHT correction;CDR.PL;0,4
+3004;SEC fee;11.02.2023 12:00:00;SEC fee;AAPL.US;-0,02
+";
+        let path = env::temp_dir().join("stock_tax_test_xtb_cash_operations.csv");
+        fs::write(&path, statement).unwrap();
+        assert!(convert(&path, None).is_err());
+        let activities = convert(&path, Some("PLN")).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(activities.len(), 3);
+        assert!(matches!(
+            &activities[0].operation,
+            Operation::Dividend { withholding_tax, .. }
+                if *withholding_tax.original.get_value() == dec!(1.9)
+        ));
+        assert!(matches!(
+            &activities[1].operation,
+            Operation::WithholdingAdjustment { value, dividend_date: None }
+                if *value.original.get_value() == dec!(-0.4)
+        ));
+        assert!(matches!(
+            &activities[2].operation,
+            Operation::Fee { value, kind: FeeKind::Other, .. }
+                if *value.original.get_value() == dec!(0.02)
+        ));
+    }
+}